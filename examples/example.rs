@@ -1,9 +1,6 @@
 use std::{sync::Arc, time::Duration};
 
-use tokio::{
-    sync::RwLock,
-    time::{interval, Instant},
-};
+use tokio::{sync::RwLock, time::interval};
 use ttl_cache_with_purging::{cache::TtlCache, purging::start_periodic_purge};
 
 const MIN_IN_SECS: u64 = 60;
@@ -14,16 +11,14 @@ async fn main() {
     // Cache setup
     let cache = Arc::new(RwLock::new(TtlCache::new()));
     let purge_interval = interval(Duration::from_secs(MIN_IN_SECS));
-    start_periodic_purge(cache.clone(), purge_interval);
+    // The purge task stops once its handle is dropped
+    let _purge_handle = start_periodic_purge(cache.clone(), purge_interval);
 
     // Add entries
     let key = "key1";
     let val = "val1";
-
-    let expires_at = Instant::now()
-        .checked_add(Duration::from_secs(HOUR_IN_SECS))
-        .unwrap();
-    cache.write().await.insert(key, val, expires_at);
+    let ttl = Duration::from_secs(HOUR_IN_SECS);
+    cache.write().await.insert_with_ttl(key, val, ttl);
 
     // Read entries
     let _cached_val = cache.read().await.get(key).unwrap();
@@ -1,17 +1,162 @@
 //! Standard cache operations.
-use std::{borrow::Borrow, collections::HashMap, hash::Hash};
+use std::{
+    borrow::Borrow,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use tokio::time::Instant;
 
+use crate::{
+    clock::{Clock, ClockSkew, RealClock},
+    eviction::{EvictionPolicy, Lru, Unbounded, Weigher},
+};
+
+mod builder;
+mod entry;
+mod expiry;
+mod listener;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "serde")]
+mod snapshot;
+mod stats;
+mod table;
+
+pub use builder::TtlCacheBuilder;
+pub use entry::{Entry, ExpiredEntry, OccupiedEntry, VacantEntry};
+pub use listener::RemovalListener;
+#[cfg(feature = "serde")]
+pub use snapshot::Snapshot;
+pub use stats::CacheStats;
+
+#[cfg(feature = "wal")]
+use crate::wal::{Journal, Record};
+use expiry::ExpiryIndex;
+#[cfg(feature = "wal")]
+pub(crate) use snapshot::SnapshotEntry;
+use stats::{PurgeCounts, StatsCounter};
+use table::EntryTable;
+
+/// The longest TTL that will be honored. Longer durations are clamped to this, which
+/// is roughly 30 years, so that far-future expirations don't overflow an `Instant`.
+const MAX_TTL: Duration = Duration::from_secs(86400 * 365 * 30);
+
 /// An instance of a cache.
+///
+/// Caches are unbounded by default. Bounded caches evict expired entries first once full,
+/// and then consult their [`EvictionPolicy`].
+///
+/// Expirations are indexed, so purging only costs work proportional to the number of
+/// expired entries.
+///
+/// The current time is read from a [`Clock`], which can be replaced to test expiration.
 #[derive(Default)]
-pub struct TtlCache<K, V> {
-    map: HashMap<K, CacheEntry<V>>,
+pub struct TtlCache<K, V, P = Unbounded, C = RealClock> {
+    map: EntryTable<K, V>,
+    expirations: ExpiryIndex,
+    /// Tags entries, so that the expiration index can tell which of its deadlines are stale.
+    next_seq: u64,
+    default_ttl: Option<Duration>,
+    time_to_idle: Option<Duration>,
+    /// How long expired entries are kept for [`TtlCache::get_stale`] before being purged.
+    grace_period: Duration,
+    clock_skew: ClockSkew,
+    weigher: Option<Box<dyn Weigher<K, V> + Send + Sync>>,
+    total_weight: u64,
+    listener: Option<Box<dyn RemovalListener<K, V> + Send + Sync>>,
+    #[cfg(feature = "wal")]
+    journal: Option<Box<dyn Journal<K, V> + Send + Sync>>,
+    /// Only allocated when stats are enabled, so that they cost nothing otherwise.
+    stats: Option<Box<StatsCounter>>,
+    bound: Option<Bound<P>>,
+    clock: C,
+}
+
+/// The limits of a bounded cache, along with the policy that enforces them.
+struct Bound<P> {
+    capacity_limit: Option<usize>,
+    max_weight: Option<u64>,
+    /// Reads only hold a shared reference to the cache, but still need to update the policy.
+    policy: Mutex<P>,
 }
 
 struct CacheEntry<V> {
     val: V,
+    inserted_at: Instant,
+    /// The hard deadline, which sliding expiration never extends past.
     expires_at: Instant,
+    idle: Option<IdleTimeout>,
+    /// The wall-clock expiration the entry was inserted with, if any, before the clock skew
+    /// policy was applied. Unlike `expires_at`, this stays meaningful on another clock.
+    system_expires_at: Option<SystemTime>,
+    weight: u64,
+    seq: u64,
+}
+
+/// Sliding expiration state that is pushed forward on every successful read.
+///
+/// Reads only hold a shared reference to the cache, so the time of the last read is
+/// stored atomically as an offset from when the entry was inserted.
+struct IdleTimeout {
+    time_to_idle: Duration,
+    last_read_nanos: AtomicU64,
+}
+
+impl<V> CacheEntry<V> {
+    fn new(
+        val: V,
+        inserted_at: Instant,
+        expires_at: Instant,
+        time_to_idle: Option<Duration>,
+    ) -> Self {
+        CacheEntry {
+            val,
+            inserted_at,
+            expires_at,
+            idle: time_to_idle.map(|time_to_idle| IdleTimeout {
+                time_to_idle: time_to_idle.min(MAX_TTL),
+                last_read_nanos: AtomicU64::new(0),
+            }),
+            system_expires_at: None,
+            weight: 1,
+            seq: 0,
+        }
+    }
+
+    /// When the entry expires, taking both the hard deadline and idle timeout into account.
+    fn deadline(&self) -> Instant {
+        match &self.idle {
+            Some(idle) => self.expires_at.min(idle.deadline(self.inserted_at)),
+            None => self.expires_at,
+        }
+    }
+
+    fn is_live(&self, now: Instant) -> bool {
+        now < self.deadline()
+    }
+
+    /// Records a read, which pushes the idle timeout forward.
+    fn touch(&self, now: Instant) {
+        if let Some(idle) = &self.idle {
+            let since_insert = now.saturating_duration_since(self.inserted_at).as_nanos();
+            idle.last_read_nanos.fetch_max(
+                u64::try_from(since_insert).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+impl IdleTimeout {
+    fn deadline(&self, inserted_at: Instant) -> Instant {
+        let last_read = Duration::from_nanos(self.last_read_nanos.load(Ordering::Relaxed));
+        inserted_at + last_read + self.time_to_idle
+    }
 }
 
 impl<K, V> TtlCache<K, V>
@@ -21,18 +166,198 @@ where
     /// Creates a new cache instance.
     pub fn new() -> Self {
         TtlCache {
-            map: HashMap::new(),
+            map: EntryTable::default(),
+            expirations: ExpiryIndex::default(),
+            next_seq: 0,
+            default_ttl: None,
+            time_to_idle: None,
+            grace_period: Duration::ZERO,
+            clock_skew: ClockSkew::Exact,
+            weigher: None,
+            total_weight: 0,
+            listener: None,
+            #[cfg(feature = "wal")]
+            journal: None,
+            stats: None,
+            bound: None,
+            clock: RealClock,
         }
     }
 
+    /// Creates a builder for configuring a new cache instance.
+    pub fn builder() -> TtlCacheBuilder<K, V> {
+        TtlCacheBuilder::new()
+    }
+}
+
+impl<K, V> TtlCache<K, V, Lru<K>>
+where
+    K: Clone + Eq + Hash,
+{
+    /// Creates a new cache instance that holds at most `capacity_limit` entries, evicting the
+    /// least-recently-used entry once full.
+    pub fn with_capacity_limit(capacity_limit: usize) -> Self {
+        TtlCache::builder()
+            .capacity_limit(capacity_limit)
+            .eviction_policy(Lru::new())
+            .build()
+    }
+}
+
+impl<K, V, P, C> TtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
     /// Adds a new value to the cache that will expire at the specified time.
+    ///
+    /// If the cache was built with a time-to-idle, the entry will also expire once it has
+    /// gone unread for that long, and `expires_at` acts as its maximum lifetime.
     pub fn insert(&mut self, key: K, val: V, expires_at: Instant) {
-        self.map.insert(key, CacheEntry { val, expires_at });
+        let entry = CacheEntry::new(val, self.clock.now(), expires_at, self.time_to_idle);
+        self.insert_entry(key, entry);
+    }
+
+    /// Adds a new value to the cache that will expire once it has gone unread for
+    /// `time_to_idle`, overriding any time-to-idle the cache was built with.
+    ///
+    /// Every successful read pushes the expiration forward, but never past `max_lifetime`
+    /// after the insert, if one is given.
+    pub fn insert_with_idle_timeout(
+        &mut self,
+        key: K,
+        val: V,
+        time_to_idle: Duration,
+        max_lifetime: Option<Duration>,
+    ) {
+        let now = self.clock.now();
+        let expires_at = self.expiration_after(max_lifetime.unwrap_or(MAX_TTL));
+        let entry = CacheEntry::new(val, now, expires_at, Some(time_to_idle));
+        self.insert_entry(key, entry);
+    }
+
+    /// Adds a new value to the cache that will expire after the specified duration.
+    ///
+    /// Durations that would overflow an `Instant` are clamped to roughly 30 years.
+    pub fn insert_with_ttl(&mut self, key: K, val: V, ttl: Duration) {
+        self.insert(key, val, self.expiration_after(ttl));
+    }
+
+    /// Adds a new value to the cache that will expire at the specified wall-clock time, such
+    /// as an expiration set by another machine.
+    ///
+    /// The expiration is adjusted by the cache's [`ClockSkew`] policy, and converted to the
+    /// cache's clock as of now. Expirations that have already passed insert an expired entry.
+    /// The wall-clock expiration is kept with the entry, so that it can be converted again on
+    /// another clock, such as when the cache is restored elsewhere.
+    pub fn insert_at_system_time(&mut self, key: K, val: V, expires_at: SystemTime) {
+        let deadline = self.expiration_after(self.time_until_system_time(expires_at));
+        let mut entry = CacheEntry::new(val, self.clock.now(), deadline, self.time_to_idle);
+        entry.system_expires_at = Some(expires_at);
+        self.insert_entry(key, entry);
+    }
+
+    /// Adds a new value to the cache that will expire at the specified number of seconds since
+    /// the Unix epoch, such as a JWT's `exp` claim.
+    ///
+    /// This is otherwise the same as [`TtlCache::insert_at_system_time`].
+    pub fn insert_at_unix_secs(&mut self, key: K, val: V, expires_at_secs: u64) {
+        let expires_at = UNIX_EPOCH
+            .checked_add(Duration::from_secs(expires_at_secs))
+            .unwrap_or_else(|| self.clock.system_now() + MAX_TTL);
+        self.insert_at_system_time(key, val, expires_at);
+    }
+
+    /// Adds a new value to the cache that will expire as described by `expiration`: either at
+    /// a specified time, or after a specified duration.
+    pub fn insert_with_expiration(&mut self, key: K, val: V, expiration: impl Into<Expiration>) {
+        match expiration.into() {
+            Expiration::At(expires_at) => self.insert(key, val, expires_at),
+            Expiration::After(ttl) => self.insert_with_ttl(key, val, ttl),
+            Expiration::AtSystemTime(expires_at) => {
+                self.insert_at_system_time(key, val, expires_at)
+            }
+        }
+    }
+
+    /// Adds a new value to the cache that will expire after the cache's default TTL.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache was not built with a default TTL.
+    pub fn insert_default(&mut self, key: K, val: V) {
+        let ttl = self
+            .default_ttl
+            .expect("insert_default requires a cache built with a default TTL");
+        self.insert_with_ttl(key, val, ttl);
+    }
+
+    /// The TTL used by [`TtlCache::insert_default`], if one was configured.
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl
+    }
+
+    /// The time-to-idle applied to every insert, if one was configured.
+    pub fn time_to_idle(&self) -> Option<Duration> {
+        self.time_to_idle
+    }
+
+    /// How long expired entries are kept for [`TtlCache::get_stale`] before being purged.
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// How wall-clock expirations are adjusted for clock skew.
+    pub fn clock_skew(&self) -> ClockSkew {
+        self.clock_skew
+    }
+
+    /// The clock the cache reads the current time from.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// The most entries the cache will hold, if it is bounded by entry count.
+    pub fn capacity_limit(&self) -> Option<usize> {
+        self.bound.as_ref().and_then(|bound| bound.capacity_limit)
+    }
+
+    /// The most total weight the cache will hold, if it is bounded by weight.
+    pub fn max_weight(&self) -> Option<u64> {
+        self.bound.as_ref().and_then(|bound| bound.max_weight)
+    }
+
+    /// The number of entries in the cache, including expired entries that have not been
+    /// purged yet.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether the cache has no entries, including expired entries that have not been
+    /// purged yet.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The total weight of the entries in the cache, including expired entries that have not
+    /// been purged yet.
+    ///
+    /// Entries weigh 1 unless the cache was built with a [`Weigher`].
+    pub fn total_weight(&self) -> u64 {
+        self.total_weight
+    }
+
+    /// A snapshot of the cache's statistics, if it was built with
+    /// [`TtlCacheBuilder::record_stats`].
+    pub fn stats(&self) -> Option<CacheStats> {
+        self.stats.as_deref().map(StatsCounter::snapshot)
     }
 
     /// Retrieves an unexpired value from the cache.
     ///
-    /// Expired entries will return `None`.
+    /// Expired entries will return `None`. Entries with a time-to-idle have their
+    /// expiration pushed forward.
     pub fn get<Q>(&self, key: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
@@ -44,16 +369,484 @@ where
 
     /// Retrieves an unexpired value from the cache, along with the expiration.
     ///
-    /// Expired entries will return `None`.
+    /// Expired entries will return `None`. Entries with a time-to-idle have their
+    /// expiration pushed forward, and the returned expiration reflects that.
     pub fn get_value_and_expiration<Q>(&self, key: &Q) -> Option<(&V, Instant)>
     where
         K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
+        let now = self.clock.now();
+        let found = self.map.get_key_value(key);
+        let Some((key, entry)) = found.filter(|(_k, e)| e.is_live(now)) else {
+            if let Some(stats) = &self.stats {
+                stats.record_miss(found.is_some());
+            }
+            return None;
+        };
+        if let Some(stats) = &self.stats {
+            stats.record_hit();
+        }
+        entry.touch(now);
+        if let Some(bound) = &self.bound {
+            bound
+                .policy
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .on_access(key);
+        }
+        Some((&entry.val, entry.deadline()))
+    }
+
+    /// Retrieves a value from the cache, even if it has expired, as long as it expired within
+    /// the cache's grace period.
+    ///
+    /// Unexpired values are read as with [`TtlCache::get`]. Expired values are returned along
+    /// with how long ago they expired, but aren't otherwise touched.
+    pub fn get_stale<Q>(&self, key: &Q) -> Option<(&V, Freshness)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(val) = self.get(key) {
+            return Some((val, Freshness::Fresh));
+        }
+        let now = self.clock.now();
+        let entry = self.map.get(key)?;
+        let expired_for = now.saturating_duration_since(entry.deadline());
+        (expired_for < self.grace_period).then_some((&entry.val, Freshness::Stale(expired_for)))
+    }
+
+    /// Like [`TtlCache::get_value_and_expiration`], but also returns when the entry was
+    /// inserted.
+    pub(crate) fn get_value_and_lifetime<Q>(&self, key: &Q) -> Option<(&V, Instant, Instant)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (val, expires_at) = self.get_value_and_expiration(key)?;
+        Some((val, self.map[key].inserted_at, expires_at))
+    }
+
+    /// The keys of unexpired entries for which `select` returns `true`, given when each
+    /// entry was inserted and when it expires.
+    ///
+    /// This scans every entry, but without touching them.
+    pub(crate) fn live_keys_where<F>(&self, mut select: F) -> Vec<K>
+    where
+        K: Clone,
+        F: FnMut(Instant, Instant) -> bool,
+    {
+        let now = self.clock.now();
+        self.map
+            .iter()
+            .filter(|(_k, e)| e.is_live(now) && select(e.inserted_at, e.deadline()))
+            .map(|(k, _e)| k.clone())
+            .collect()
+    }
+
+    /// Gets the given key's entry in the cache for in-place manipulation.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, P, C> {
+        Entry::new(self, key)
+    }
+
+    /// Removes a key from the cache, returning the value if it was unexpired.
+    ///
+    /// Expired entries are removed as well, but will return `None`.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(key).map(|(val, _expires_at)| val)
+    }
+
+    /// Removes a key from the cache, returning the value along with the expiration
+    /// if it was unexpired.
+    ///
+    /// Expired entries are removed as well, but will return `None`.
+    pub fn remove_entry<Q>(&mut self, key: &Q) -> Option<(V, Instant)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.clock.now();
+        self.remove_key(key, RemovalCause::Explicit)
+            .map(|(_k, e)| e)
+            .filter(|e| e.is_live(now))
+            .map(|e| {
+                let deadline = e.deadline();
+                (e.val, deadline)
+            })
+    }
+
+    /// Removes all entries from the cache.
+    pub fn clear(&mut self) {
+        let listener = self.listener.as_deref();
+        for (key, entry) in self.map.drain() {
+            if let Some(listener) = listener {
+                listener.on_removal(&key, &entry.val, RemovalCause::Explicit);
+            }
+        }
+        self.expirations.clear();
+        self.total_weight = 0;
+        if let Some(policy) = self.policy_mut() {
+            policy.clear();
+        }
+        #[cfg(feature = "wal")]
+        if let Some(journal) = &self.journal {
+            journal.append(Record::Clear);
+        }
+    }
+
+    /// Removes every entry for which the predicate returns `true`, regardless of
+    /// whether it has expired.
+    pub fn invalidate_if<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.retain_entries(RemovalCause::Explicit, |k, e| !predicate(k, &e.val));
+    }
+
+    /// Computes the expiration for an entry inserted now with the given TTL.
+    fn expiration_after(&self, ttl: Duration) -> Instant {
+        self.clock.now() + ttl.min(MAX_TTL)
+    }
+
+    /// How long is left until a wall-clock expiration, after adjusting it for clock skew, or
+    /// zero if it has already passed.
+    fn time_until_system_time(&self, expires_at: SystemTime) -> Duration {
+        self.clock_skew
+            .adjust(expires_at)
+            .duration_since(self.clock.system_now())
+            .unwrap_or_default()
+    }
+
+    /// Inserts an entry and returns a mutable reference to its value.
+    fn insert_and_get_mut(&mut self, key: K, val: V, expires_at: Instant) -> &mut V {
+        let entry = CacheEntry::new(val, self.clock.now(), expires_at, self.time_to_idle);
+        &mut self.insert_entry(key, entry).val
+    }
+
+    /// Inserts an entry, making room for it first if the cache is bounded.
+    fn insert_entry(&mut self, key: K, mut entry: CacheEntry<V>) -> &mut CacheEntry<V> {
+        if let Some(weigher) = &self.weigher {
+            entry.weight = weigher.weigh(&key, &entry.val);
+        }
+        self.make_room(&key, entry.weight);
+
+        let hash = self.map.hash(&key);
+        entry.seq = self.take_seq();
+        self.schedule(hash, entry.deadline(), entry.seq);
+        self.total_weight += entry.weight;
+        let mut policy = self.bound.as_mut().map(Bound::policy_mut);
+        let (key, entry, replaced) = self.map.insert(hash, key, entry);
+        match replaced {
+            Some(replaced) => {
+                self.total_weight -= replaced.weight;
+                let overwrite = replaced.is_live(self.clock.now());
+                if let Some(stats) = &self.stats {
+                    stats.record_insert(overwrite);
+                }
+                if let Some(listener) = &self.listener {
+                    let cause = if overwrite {
+                        RemovalCause::Replaced
+                    } else {
+                        RemovalCause::Expired
+                    };
+                    listener.on_removal(key, &replaced.val, cause);
+                }
+            }
+            None => {
+                if let Some(stats) = &self.stats {
+                    stats.record_insert(false);
+                }
+            }
+        }
+        if let Some(policy) = policy.as_mut() {
+            policy.on_insert(key);
+        }
+        #[cfg(feature = "wal")]
+        if let Some(journal) = &self.journal {
+            journal.append(Record::Insert {
+                written_at: self.clock.system_now(),
+                entry: SnapshotEntry::borrowed(key, entry, self.clock.now()),
+            });
+        }
+        entry
+    }
+
+    /// Evicts entries until an entry of the given weight can be inserted under `key`,
+    /// expired entries first, even those still in their grace period.
+    ///
+    /// An entry that is heavier than the maximum weight on its own evicts everything the
+    /// policy is willing to evict, and is then inserted anyway.
+    fn make_room(&mut self, key: &K, weight: u64) {
+        if !self.is_over_bound(key, weight) {
+            return;
+        }
+
+        let started = self.stats.is_some().then(std::time::Instant::now);
+        let purged = self.purge_counted(usize::MAX, Duration::ZERO).removed;
+        // Only recorded if it made room, so that every insert into a full cache doesn't count
+        // as a purge.
+        if purged > 0 {
+            self.record_purge(purged, started);
+        }
+        while self.is_over_bound(key, weight) {
+            let Some(victim) = self.policy_mut().and_then(|policy| policy.evict()) else {
+                break;
+            };
+            if let Some((key, evicted)) = self.map.remove_entry(&victim) {
+                self.total_weight -= evicted.weight;
+                if let Some(stats) = &self.stats {
+                    stats.record_eviction();
+                }
+                self.log_removal(&key);
+                self.notify_removal(&key, &evicted.val, RemovalCause::Capacity);
+            }
+        }
+    }
+
+    /// Whether inserting an entry of the given weight under `key` would exceed the cache's
+    /// bounds.
+    fn is_over_bound(&self, key: &K, weight: u64) -> bool {
+        let Some(bound) = &self.bound else {
+            return false;
+        };
+        let replaced = self.map.get(key);
+        let len = self.map.len() + usize::from(replaced.is_none());
+        let total_weight = self.total_weight - replaced.map_or(0, |e| e.weight) + weight;
+        bound.capacity_limit.is_some_and(|limit| len > limit)
+            || bound.max_weight.is_some_and(|max| total_weight > max)
+    }
+
+    /// Removes every entry for which `keep` returns `false`.
+    fn retain_entries<F>(&mut self, cause: RemovalCause, mut keep: F)
+    where
+        F: FnMut(&K, &CacheEntry<V>) -> bool,
+    {
+        let mut policy = self.bound.as_mut().map(Bound::policy_mut);
+        let total_weight = &mut self.total_weight;
+        let listener = self.listener.as_deref();
+        #[cfg(feature = "wal")]
+        let journal = self.journal.as_deref();
+        self.map.retain(|k, e| {
+            if keep(k, e) {
+                return true;
+            }
+            *total_weight -= e.weight;
+            if let Some(policy) = policy.as_mut() {
+                policy.on_remove(k, cause);
+            }
+            if let Some(listener) = listener {
+                listener.on_removal(k, &e.val, cause);
+            }
+            #[cfg(feature = "wal")]
+            if let Some(journal) = journal {
+                journal.append(Record::Remove { key: k });
+            }
+            false
+        });
+    }
+
+    /// Re-indexes the expiration of an entry whose deadline was changed in place.
+    fn reschedule(&mut self, key: &K) {
+        let seq = self.take_seq();
+        let hash = self.map.hash(key);
+        let entry = self.entry_mut(key);
+        entry.seq = seq;
+        let deadline = entry.deadline();
+        self.schedule(hash, deadline, seq);
+    }
+
+    /// Indexes an entry that expires at `deadline`, to be purged once its grace period has
+    /// passed as well.
+    ///
+    /// The index is rebuilt before the deadline is added, since the map may not hold the entry
+    /// yet.
+    fn schedule(&mut self, hash: u64, deadline: Instant, seq: u64) {
+        let grace_period = self.grace_period;
+        if self.expirations.needs_rebuild(self.map.len()) {
+            let map = &self.map;
+            self.expirations.rebuild(
+                map.iter()
+                    .map(|(k, e)| (map.hash(k), e.deadline() + grace_period, e.seq)),
+            );
+        }
+        self.expirations
+            .schedule(hash, deadline + grace_period, seq);
+    }
+
+    fn take_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    /// Replaces the value of a present entry, keeping its expiration, and returns the old value.
+    ///
+    /// The entry is set aside while making room for the new value, so that it can't be evicted
+    /// to make room for itself.
+    fn replace_val(&mut self, key: &K, val: V) -> V {
+        let (stored_key, mut entry) = self
+            .map
+            .remove_entry(key)
+            .expect("entries are present in the cache");
+        self.total_weight -= entry.weight;
+        let old = std::mem::replace(&mut entry.val, val);
+        if let Some(weigher) = &self.weigher {
+            entry.weight = weigher.weigh(key, &entry.val);
+        }
+        self.make_room(key, entry.weight);
+
+        self.total_weight += entry.weight;
+        // Tracks the key again in case the policy chose it as a victim.
+        if let Some(policy) = self.policy_mut() {
+            policy.on_insert(key);
+        }
+        // Making room may have purged the entry's expiration from the index.
+        let hash = self.map.hash(key);
+        entry.seq = self.take_seq();
+        self.schedule(hash, entry.deadline(), entry.seq);
+        self.map.insert(hash, stored_key, entry);
+        if let Some(stats) = &self.stats {
+            stats.record_insert(true);
+        }
+        self.log_insert(key);
+        self.notify_removal(key, &old, RemovalCause::Replaced);
+        old
+    }
+
+    /// Removes a key from the cache, regardless of whether it has expired.
+    fn remove_key<Q>(&mut self, key: &Q, cause: RemovalCause) -> Option<(K, CacheEntry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (key, entry) = self.map.remove_entry(key)?;
+        self.forget_entry(&key, &entry, cause);
+        Some((key, entry))
+    }
+
+    /// Accounts for an entry that was taken out of the map.
+    fn forget_entry(&mut self, key: &K, entry: &CacheEntry<V>, cause: RemovalCause) {
+        self.total_weight -= entry.weight;
+        if let Some(policy) = self.policy_mut() {
+            policy.on_remove(key, cause);
+        }
+        // Purges are logged as a whole.
+        if cause != RemovalCause::Expired {
+            self.log_removal(key);
+        }
+        self.notify_removal(key, &entry.val, cause);
+    }
+
+    /// Logs the current value and expiration of an entry that was changed in place.
+    fn log_insert(&self, key: &K) {
+        #[cfg(feature = "wal")]
+        if let Some(journal) = &self.journal {
+            journal.append(Record::Insert {
+                written_at: self.clock.system_now(),
+                entry: SnapshotEntry::borrowed(key, &self.map[key], self.clock.now()),
+            });
+        }
+        #[cfg(not(feature = "wal"))]
+        let _ = key;
+    }
+
+    fn log_removal(&self, key: &K) {
+        #[cfg(feature = "wal")]
+        if let Some(journal) = &self.journal {
+            journal.append(Record::Remove { key });
+        }
+        #[cfg(not(feature = "wal"))]
+        let _ = key;
+    }
+
+    fn notify_removal(&self, key: &K, val: &V, cause: RemovalCause) {
+        if let Some(listener) = &self.listener {
+            listener.on_removal(key, val, cause);
+        }
+    }
+
+    fn policy_mut(&mut self) -> Option<&mut P> {
+        self.bound.as_mut().map(Bound::policy_mut)
+    }
+
+    /// Looks up an entry that is known to be present.
+    fn entry_mut(&mut self, key: &K) -> &mut CacheEntry<V> {
         self.map
-            .get(key)
-            .filter(|e| e.expires_at > Instant::now())
-            .map(|e| (&e.val, e.expires_at))
+            .get_mut(key)
+            .expect("entries are present in the cache")
+    }
+}
+
+impl<P> Bound<P> {
+    fn policy_mut(&mut self) -> &mut P {
+        self.policy
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Why an entry was removed from the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RemovalCause {
+    /// The entry expired, and was purged, evicted to make room, or overwritten.
+    Expired,
+    /// The entry was removed by the caller.
+    Explicit,
+    /// The entry was overwritten while it was still unexpired.
+    Replaced,
+    /// The entry was evicted by the eviction policy to keep a bounded cache within its limits.
+    Capacity,
+}
+
+/// Whether a value read through [`TtlCache::get_stale`] has expired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    /// The value is unexpired.
+    Fresh,
+    /// The value expired the given duration ago, but is still within the cache's grace period.
+    Stale(Duration),
+}
+
+impl Freshness {
+    /// Whether the value has expired.
+    pub fn is_stale(self) -> bool {
+        matches!(self, Freshness::Stale(_))
+    }
+}
+
+/// When an entry expires: either at a point in time, as with [`TtlCache::insert`], after a
+/// TTL, as with [`TtlCache::insert_with_ttl`], or at a wall-clock time, as with
+/// [`TtlCache::insert_at_system_time`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiration {
+    /// The entry expires at the given time.
+    At(Instant),
+    /// The entry expires once the given duration has passed after it is inserted.
+    After(Duration),
+    /// The entry expires at the given wall-clock time, adjusted for clock skew.
+    AtSystemTime(SystemTime),
+}
+
+impl From<Instant> for Expiration {
+    fn from(expires_at: Instant) -> Self {
+        Expiration::At(expires_at)
+    }
+}
+
+impl From<Duration> for Expiration {
+    fn from(ttl: Duration) -> Self {
+        Expiration::After(ttl)
+    }
+}
+
+impl From<SystemTime> for Expiration {
+    fn from(expires_at: SystemTime) -> Self {
+        Expiration::AtSystemTime(expires_at)
     }
 }
 
@@ -63,17 +856,129 @@ where
 pub trait Purgeable {
     /// Purges expired entries from the cache.
     fn purge_expired(&mut self);
+
+    /// Purges expired entries from the cache, doing at most `max_entries` units of work,
+    /// and returns whether there may be more to purge.
+    ///
+    /// Each call resumes where the previous one left off, so that a large purge can be
+    /// split into chunks that each hold the cache's lock only briefly. Implementations that
+    /// can't be split purge everything at once.
+    fn purge_expired_bounded(&mut self, max_entries: usize) -> bool {
+        let _ = max_entries;
+        self.purge_expired();
+        false
+    }
 }
 
-impl<K, V> Purgeable for TtlCache<K, V> {
+impl<K, V, P, C> Purgeable for TtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
     fn purge_expired(&mut self) {
-        let now = Instant::now();
-        self.map.retain(|_k, v| now < v.expires_at)
+        self.purge_recorded(usize::MAX);
+    }
+
+    /// Each unit of work is one due deadline in the expiration index, which is also the
+    /// cursor that the next call resumes from.
+    fn purge_expired_bounded(&mut self, max_entries: usize) -> bool {
+        self.purge_recorded(max_entries).more
+    }
+}
+
+impl<K, V, P, C> TtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Purges expired entries, as requested through [`Purgeable`], and records the purge.
+    ///
+    /// With the `tracing` feature, the number of deadlines scanned and entries removed are
+    /// recorded as the `scanned` and `removed` fields of the current span, which is the
+    /// `purge` span when purging from a background task.
+    fn purge_recorded(&mut self, max_entries: usize) -> PurgeCounts {
+        // Only timed for the stats, so that purges don't read the time otherwise.
+        let started = self.stats.is_some().then(std::time::Instant::now);
+        let counts = self.purge_counted(max_entries, self.grace_period);
+        self.record_purge(counts.removed, started);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current()
+            .record("scanned", counts.scanned)
+            .record("removed", counts.removed);
+        #[cfg(not(feature = "tracing"))]
+        let _ = counts.scanned;
+        counts
+    }
+
+    /// Records a purge that removed `purged` entries and was started at `started`, if the
+    /// cache records stats.
+    fn record_purge(&self, purged: u64, started: Option<std::time::Instant>) {
+        if let (Some(stats), Some(started)) = (&self.stats, started) {
+            stats.record_purge(purged, started.elapsed(), self.map.len());
+        }
+    }
+
+    /// Purges entries that expired at least `grace_period` ago, visiting at most
+    /// `max_entries` due deadlines.
+    ///
+    /// The index orders entries by their deadline plus the cache's grace period, so a shorter
+    /// grace period only moves how far into the index the purge reaches.
+    fn purge_counted(&mut self, max_entries: usize, grace_period: Duration) -> PurgeCounts {
+        let now = self.clock.now();
+        let due_by = now + self.grace_period.saturating_sub(grace_period);
+        let mut scanned = 0;
+        let mut purged = 0;
+        #[cfg(feature = "wal")]
+        let mut purged_keys = Vec::new();
+        for _ in 0..max_entries {
+            let Some((hash, seq)) = self.expirations.pop_due(due_by) else {
+                break;
+            };
+            scanned += 1;
+            let Some(entry) = self.map.get_scheduled(hash, seq) else {
+                continue;
+            };
+            if now < entry.deadline() + grace_period {
+                // Sliding expiration pushed the deadline back since it was scheduled, or the
+                // entry is still in its grace period.
+                let purge_at = entry.deadline() + self.grace_period;
+                self.expirations.schedule(hash, purge_at, seq);
+                continue;
+            }
+            let (key, entry) = self
+                .map
+                .remove_scheduled(hash, seq)
+                .expect("the entry was just found");
+            self.forget_entry(&key, &entry, RemovalCause::Expired);
+            purged += 1;
+            #[cfg(feature = "wal")]
+            if self.journal.is_some() {
+                purged_keys.push(key);
+            }
+        }
+        #[cfg(feature = "wal")]
+        if let Some(journal) = self.journal.as_ref().filter(|_| !purged_keys.is_empty()) {
+            journal.append(Record::Purge {
+                keys: purged_keys.iter().collect(),
+            });
+        }
+        PurgeCounts {
+            scanned,
+            removed: purged,
+            more: self.expirations.has_due(due_by),
+        }
     }
 }
 
 #[cfg(test)]
 pub(crate) mod test_helpers {
+    #[cfg(feature = "tracing")]
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
 
     use super::Purgeable;
 
@@ -87,26 +992,135 @@ pub(crate) mod test_helpers {
             self.purge_expired_called = true;
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
+    /// A subscriber that records the fields of every span, and which spans are entered, so
+    /// that tests can tell which span a field was recorded on.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    pub(crate) struct SpanRecorder {
+        /// Every span, indexed by its ID minus one.
+        spans: Arc<Mutex<Vec<RecordedSpan>>>,
+        /// The IDs of the entered spans, innermost last.
+        entered: Arc<Mutex<Vec<tracing::span::Id>>>,
+    }
 
-    use lazy_static::lazy_static;
-    use tokio::time::Instant;
+    #[cfg(feature = "tracing")]
+    struct RecordedSpan {
+        metadata: &'static tracing::Metadata<'static>,
+        fields: HashMap<&'static str, String>,
+    }
 
-    use crate::cache::{Purgeable, TtlCache};
+    #[cfg(feature = "tracing")]
+    impl SpanRecorder {
+        /// The latest value of every field of each span named `name`, formatted with `Debug`.
+        pub(crate) fn spans(&self, name: &str) -> Vec<HashMap<&'static str, String>> {
+            self.spans
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|span| span.metadata.name() == name)
+                .map(|span| span.fields.clone())
+                .collect()
+        }
 
-    lazy_static! {
-        static ref UNEXPIRED_TIME: Instant = Instant::now()
-            .checked_add(Duration::from_secs(86400))
-            .unwrap();
-        static ref EXPIRED_TIME: Instant =
-            Instant::now().checked_sub(Duration::from_secs(10)).unwrap();
+        /// The name of the innermost entered span, if any.
+        pub(crate) fn current_name(&self) -> Option<&'static str> {
+            let current = self.entered.lock().unwrap().last()?.clone();
+            Some(
+                self.spans.lock().unwrap()[Self::index(&current)]
+                    .metadata
+                    .name(),
+            )
+        }
+
+        fn index(id: &tracing::span::Id) -> usize {
+            usize::try_from(id.into_u64() - 1).unwrap()
+        }
     }
 
-    #[test]
+    /// A visitor that stores the value of every field it visits in `fields`.
+    #[cfg(feature = "tracing")]
+    fn field_recorder<'a>(
+        fields: &'a mut HashMap<&'static str, String>,
+    ) -> impl FnMut(&tracing::field::Field, &dyn std::fmt::Debug) + 'a {
+        |field, value| {
+            fields.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attributes: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut span = RecordedSpan {
+                metadata: attributes.metadata(),
+                fields: HashMap::new(),
+            };
+            attributes.record(&mut field_recorder(&mut span.fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut field_recorder(&mut spans[Self::index(span)].fields));
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, span: &tracing::span::Id) {
+            let mut entered = self.entered.lock().unwrap();
+            if let Some(position) = entered.iter().rposition(|id| id == span) {
+                entered.remove(position);
+            }
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            let Some(current) = self.entered.lock().unwrap().last().cloned() else {
+                return tracing_core::span::Current::none();
+            };
+            let metadata = self.spans.lock().unwrap()[Self::index(&current)].metadata;
+            tracing_core::span::Current::new(current, metadata)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use lazy_static::lazy_static;
+    use tokio::{
+        sync::mpsc::unbounded_channel,
+        time::{advance, Instant},
+    };
+
+    use crate::cache::{Entry, Freshness, Purgeable, RemovalCause, TtlCache};
+    use crate::clock::{Clock, ClockSkew, MockClock};
+    use crate::eviction::{Lfu, Lru};
+
+    lazy_static! {
+        static ref UNEXPIRED_TIME: Instant = Instant::now()
+            .checked_add(Duration::from_secs(86400))
+            .unwrap();
+        static ref EXPIRED_TIME: Instant =
+            Instant::now().checked_sub(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
     fn when_adding_an_active_entry_to_the_cache_then_it_is_present() {
         // Arrange
         let mut cache = TtlCache::new();
@@ -160,6 +1174,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_get_then_check)]
     fn given_a_mixture_of_expired_entries_and_active_entries_when_deleting_the_expired_entries_then_the_expired_entries_are_removed_and_the_active_entries_remain(
     ) {
         // Arrange
@@ -176,4 +1191,674 @@ mod tests {
         assert!(cache.map.get(unexpired).is_some());
         assert!(cache.map.get(expired).is_none());
     }
+
+    #[test]
+    fn given_an_active_entry_in_the_cache_when_removing_it_then_the_value_is_returned_and_it_is_no_longer_present(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        let val = "val";
+        cache.insert(key, val, *UNEXPIRED_TIME);
+
+        // Act
+        let removed = cache.remove_entry(key);
+
+        // Assert
+        assert_eq!(removed, Some((val, *UNEXPIRED_TIME)));
+        assert!(cache.get(key).is_none());
+        assert!(cache.remove(key).is_none());
+    }
+
+    #[test]
+    fn given_an_expired_entry_in_the_cache_when_removing_it_then_nothing_is_returned_and_it_is_deleted(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        cache.insert(key, "val", *EXPIRED_TIME);
+
+        // Act
+        let removed = cache.remove(key);
+
+        // Assert
+        assert!(removed.is_none());
+        assert!(!cache.map.contains_key(key));
+    }
+
+    #[test]
+    fn given_entries_in_the_cache_when_invalidating_by_predicate_then_only_matching_entries_are_removed(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        cache.insert("revoked", "val1", *UNEXPIRED_TIME);
+        cache.insert("valid", "val2", *UNEXPIRED_TIME);
+
+        // Act
+        cache.invalidate_if(|k, _v| k.starts_with("revoked"));
+
+        // Assert
+        assert!(cache.get("revoked").is_none());
+        assert_eq!(*cache.get("valid").unwrap(), "val2");
+    }
+
+    #[test]
+    fn given_entries_in_the_cache_when_clearing_it_then_all_entries_are_removed() {
+        // Arrange
+        let mut cache = TtlCache::new();
+        cache.insert("key1", "val1", *UNEXPIRED_TIME);
+        cache.insert("key2", "val2", *EXPIRED_TIME);
+
+        // Act
+        cache.clear();
+
+        // Assert
+        assert!(cache.map.is_empty());
+    }
+
+    #[test]
+    fn when_adding_an_entry_with_a_ttl_then_it_expires_after_the_ttl() {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        let ttl = Duration::from_secs(60);
+        let earliest = Instant::now() + ttl;
+
+        // Act
+        cache.insert_with_ttl(key, "val", ttl);
+
+        // Assert
+        let (_val, expires_at) = cache.get_value_and_expiration(key).unwrap();
+        assert!(expires_at >= earliest);
+        assert!(expires_at <= Instant::now() + ttl);
+    }
+
+    #[test]
+    fn when_adding_an_entry_with_a_far_future_ttl_then_the_expiration_saturates() {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+
+        // Act
+        cache.insert_with_ttl(key, "val", Duration::MAX);
+
+        // Assert
+        assert_eq!(*cache.get(key).unwrap(), "val");
+    }
+
+    #[test]
+    fn given_a_cache_with_a_default_ttl_when_adding_a_default_entry_then_it_uses_the_default_ttl() {
+        // Arrange
+        let ttl = Duration::from_secs(60);
+        let mut cache = TtlCache::builder().default_ttl(ttl).build();
+        let key = "key";
+
+        // Act
+        cache.insert_default(key, "val");
+
+        // Assert
+        assert_eq!(cache.default_ttl(), Some(ttl));
+        let (_val, expires_at) = cache.get_value_and_expiration(key).unwrap();
+        assert!(expires_at <= Instant::now() + ttl);
+    }
+
+    #[test]
+    fn given_an_expired_entry_in_the_cache_when_using_the_entry_api_then_it_is_reported_as_expired_and_can_be_reused(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        cache.insert(key, "stale", *EXPIRED_TIME);
+
+        // Act
+        let entry = cache.entry(key);
+
+        // Assert
+        match entry {
+            Entry::Expired(e) => {
+                assert_eq!(*e.stale_value(), "stale");
+                e.insert("fresh", *UNEXPIRED_TIME);
+            }
+            _ => panic!("expected an expired entry"),
+        }
+        assert_eq!(*cache.get(key).unwrap(), "fresh");
+    }
+
+    #[test]
+    fn given_entries_in_the_cache_when_inserting_through_the_entry_api_then_only_vacant_and_expired_entries_are_replaced(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        cache.insert("live", "val1", *UNEXPIRED_TIME);
+        cache.insert("expired", "val2", *EXPIRED_TIME);
+
+        // Act
+        cache.entry("live").or_insert("new", *UNEXPIRED_TIME);
+        cache.entry("expired").or_insert("new", *UNEXPIRED_TIME);
+        cache.entry("vacant").or_insert("new", *UNEXPIRED_TIME);
+
+        // Assert
+        assert_eq!(*cache.get("live").unwrap(), "val1");
+        assert_eq!(*cache.get("expired").unwrap(), "new");
+        assert_eq!(*cache.get("vacant").unwrap(), "new");
+    }
+
+    #[test]
+    fn given_an_active_entry_in_the_cache_when_extending_it_through_the_entry_api_then_the_expiration_is_updated(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        let original_expiration = Instant::now() + Duration::from_secs(10);
+        cache.insert(key, 1, original_expiration);
+
+        // Act
+        if let Entry::Occupied(mut e) = cache.entry(key) {
+            *e.get_mut() += 1;
+            assert_eq!(e.set_expires_at(*UNEXPIRED_TIME), original_expiration);
+        }
+
+        // Assert
+        assert_eq!(
+            cache.get_value_and_expiration(key),
+            Some((&2, *UNEXPIRED_TIME))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_cache_with_a_time_to_idle_when_an_entry_is_read_then_its_expiration_slides_forward(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .time_to_idle(Duration::from_secs(10))
+            .build();
+        let key = "key";
+        cache.insert(key, "val", *UNEXPIRED_TIME);
+
+        // Act
+        for _ in 0..3 {
+            advance(Duration::from_secs(8)).await;
+            assert!(cache.get(key).is_some());
+        }
+
+        // Assert
+        advance(Duration::from_secs(11)).await;
+        assert!(cache.get(key).is_none());
+        cache.purge_expired();
+        assert!(cache.map.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_an_entry_with_an_idle_timeout_and_max_lifetime_when_it_is_read_continuously_then_it_expires_at_the_max_lifetime(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        cache.insert_with_idle_timeout(
+            key,
+            "val",
+            Duration::from_secs(10),
+            Some(Duration::from_secs(25)),
+        );
+
+        // Act
+        advance(Duration::from_secs(8)).await;
+        let first_read_at = Instant::now();
+        let (_val, expires_at) = cache.get_value_and_expiration(key).unwrap();
+        advance(Duration::from_secs(8)).await;
+        let read_before_max_lifetime = cache.get(key).is_some();
+        advance(Duration::from_secs(9)).await;
+
+        // Assert
+        assert_eq!(expires_at, first_read_at + Duration::from_secs(10));
+        assert!(read_before_max_lifetime);
+        assert!(cache.get(key).is_none());
+    }
+
+    #[test]
+    fn given_a_full_cache_with_a_capacity_limit_when_adding_an_entry_then_the_least_recently_used_entry_is_evicted(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::with_capacity_limit(2);
+        cache.insert("key1", "val1", *UNEXPIRED_TIME);
+        cache.insert("key2", "val2", *UNEXPIRED_TIME);
+        cache.get("key1");
+
+        // Act
+        cache.insert("key3", "val3", *UNEXPIRED_TIME);
+
+        // Assert
+        assert_eq!(cache.map.len(), 2);
+        assert!(cache.get("key1").is_some());
+        assert!(cache.get("key2").is_none());
+        assert!(cache.get("key3").is_some());
+    }
+
+    #[test]
+    fn given_a_full_cache_with_an_expired_entry_when_adding_an_entry_then_the_expired_entry_is_evicted_first(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::with_capacity_limit(2);
+        cache.insert("least_recently_used", "val1", *UNEXPIRED_TIME);
+        cache.insert("expired", "val2", *EXPIRED_TIME);
+
+        // Act
+        cache.insert("new", "val3", *UNEXPIRED_TIME);
+
+        // Assert
+        assert_eq!(cache.map.len(), 2);
+        assert!(cache.get("least_recently_used").is_some());
+        assert!(cache.get("new").is_some());
+    }
+
+    #[test]
+    fn given_a_full_cache_with_an_entry_in_its_grace_period_when_adding_an_entry_then_the_stale_entry_is_evicted_first(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .capacity_limit(2)
+            .eviction_policy(Lru::new())
+            .grace_period(Duration::from_secs(60))
+            .build();
+        cache.insert("least_recently_used", "val1", *UNEXPIRED_TIME);
+        cache.insert("stale", "val2", *EXPIRED_TIME);
+
+        // Act
+        cache.insert("new", "val3", *UNEXPIRED_TIME);
+
+        // Assert
+        assert_eq!(cache.map.len(), 2);
+        assert!(cache.get("least_recently_used").is_some());
+        assert!(cache.get("new").is_some());
+        assert_eq!(cache.get_stale("stale"), None);
+    }
+
+    #[test]
+    fn given_a_full_cache_with_an_lfu_policy_when_adding_an_entry_then_the_least_frequently_used_entry_is_evicted(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .capacity_limit(2)
+            .eviction_policy(Lfu::new())
+            .build();
+        cache.insert("frequent", "val1", *UNEXPIRED_TIME);
+        cache.insert("infrequent", "val2", *UNEXPIRED_TIME);
+        cache.get("frequent");
+
+        // Act
+        cache.insert("new", "val3", *UNEXPIRED_TIME);
+
+        // Assert
+        assert!(cache.get("frequent").is_some());
+        assert!(cache.get("infrequent").is_none());
+        assert!(cache.get("new").is_some());
+    }
+
+    #[test]
+    fn given_a_cache_with_a_max_weight_when_adding_heavy_entries_then_entries_are_evicted_to_stay_under_the_max_weight(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .max_weight(10)
+            .weigher(|_k: &&str, v: &String| v.len() as u64)
+            .eviction_policy(Lru::new())
+            .build();
+        cache.insert("flag", "1".to_string(), *UNEXPIRED_TIME);
+        cache.insert("small", "1234".to_string(), *UNEXPIRED_TIME);
+        cache.get("flag");
+
+        // Act
+        cache.insert("large", "123456".to_string(), *UNEXPIRED_TIME);
+
+        // Assert
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.total_weight(), 7);
+        assert!(cache.get("small").is_none());
+    }
+
+    #[test]
+    fn given_a_cache_with_a_max_weight_when_replacing_a_value_through_the_entry_api_then_other_entries_are_evicted(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .max_weight(10)
+            .weigher(|_k: &&str, v: &String| v.len() as u64)
+            .eviction_policy(Lru::new())
+            .build();
+        cache.insert("key", "12".to_string(), *UNEXPIRED_TIME);
+        cache.insert("other", "1234".to_string(), *UNEXPIRED_TIME);
+
+        // Act
+        let old = match cache.entry("key") {
+            Entry::Occupied(mut e) => e.insert("12345678".to_string()),
+            _ => panic!("the entry should be occupied"),
+        };
+
+        // Assert
+        assert_eq!(old, "12");
+        assert_eq!(cache.total_weight(), 8);
+        assert_eq!(cache.get("key").map(String::as_str), Some("12345678"));
+        assert!(cache.get("other").is_none());
+    }
+
+    #[test]
+    fn given_a_weighted_entry_when_it_is_replaced_or_removed_then_the_total_weight_is_updated() {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .weigher(|_k: &&str, v: &Vec<u8>| v.len() as u64)
+            .build();
+        cache.insert("key", vec![0; 4], *UNEXPIRED_TIME);
+        cache.insert("expired", vec![0; 8], *EXPIRED_TIME);
+
+        // Act
+        cache.insert("key", vec![0; 16], *UNEXPIRED_TIME);
+        let replaced_weight = cache.total_weight();
+        cache.purge_expired();
+        let purged_weight = cache.total_weight();
+        cache.remove("key");
+
+        // Assert
+        assert_eq!(replaced_weight, 24);
+        assert_eq!(purged_weight, 16);
+        assert_eq!(cache.total_weight(), 0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn given_keys_that_cannot_be_cloned_when_purging_then_expired_entries_are_removed() {
+        // Arrange
+        #[derive(PartialEq, Eq, Hash)]
+        struct Key(u32);
+        let mut cache = TtlCache::new();
+        cache.insert(Key(1), "val", *UNEXPIRED_TIME);
+        cache.insert(Key(2), "val", *EXPIRED_TIME);
+
+        // Act
+        cache.purge_expired();
+
+        // Assert
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&Key(1)), Some(&"val"));
+    }
+
+    #[test]
+    fn given_an_entry_whose_expiration_was_moved_earlier_when_purging_then_it_is_removed() {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        cache.insert(key, "val", *UNEXPIRED_TIME);
+        if let Entry::Occupied(mut e) = cache.entry(key) {
+            e.set_expires_at(*EXPIRED_TIME);
+        }
+
+        // Act
+        cache.purge_expired();
+
+        // Assert
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn given_an_entry_that_is_overwritten_many_times_when_purging_then_only_the_latest_expiration_counts(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        let key = "key";
+        for _ in 0..1000 {
+            cache.insert(key, "expired", *EXPIRED_TIME);
+        }
+
+        // Rebuilding the index at any point must keep the latest expiration.
+        let mut overwritten_caches = (1..=200)
+            .map(|overwrites| {
+                let mut cache = TtlCache::new();
+                for _ in 0..overwrites {
+                    cache.insert(key, "expired", *EXPIRED_TIME);
+                }
+                cache
+            })
+            .collect::<Vec<_>>();
+
+        // Act
+        cache.insert(key, "val", *UNEXPIRED_TIME);
+        cache.purge_expired();
+        for cache in &mut overwritten_caches {
+            cache.purge_expired();
+        }
+
+        // Assert
+        assert_eq!(*cache.get(key).unwrap(), "val");
+        assert!(!cache.expirations.needs_rebuild(cache.len()));
+        assert!(overwritten_caches.iter().all(TtlCache::is_empty));
+    }
+
+    #[test]
+    fn given_many_expired_entries_when_purging_in_bounded_chunks_then_each_chunk_removes_at_most_the_bound(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::new();
+        for key in 0..10 {
+            cache.insert(key, "val", *EXPIRED_TIME);
+        }
+        cache.insert(10, "val", *UNEXPIRED_TIME);
+
+        // Act
+        let more_after_first_chunk = cache.purge_expired_bounded(4);
+        let len_after_first_chunk = cache.len();
+        let more_after_second_chunk = cache.purge_expired_bounded(4);
+        let more_after_last_chunk = cache.purge_expired_bounded(4);
+
+        // Assert
+        assert!(more_after_first_chunk);
+        assert_eq!(len_after_first_chunk, 7);
+        assert!(more_after_second_chunk);
+        assert!(!more_after_last_chunk);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn given_a_cache_with_a_removal_listener_when_entries_leave_the_cache_then_the_listener_is_notified_with_the_cause(
+    ) {
+        // Arrange
+        let removals = Arc::new(Mutex::new(Vec::new()));
+        let listener_removals = removals.clone();
+        let mut cache = TtlCache::builder()
+            .capacity_limit(2)
+            .eviction_policy(Lru::new())
+            .removal_listener(move |key: &&'static str, val: &&'static str, cause| {
+                listener_removals.lock().unwrap().push((*key, *val, cause));
+            })
+            .build();
+        cache.insert("expired", "val", *EXPIRED_TIME);
+        cache.insert("replaced", "old", *UNEXPIRED_TIME);
+
+        // Act
+        cache.purge_expired();
+        cache.insert("replaced", "new", *UNEXPIRED_TIME);
+        cache.insert("evictor", "val", *UNEXPIRED_TIME);
+        cache.insert("evictor2", "val", *UNEXPIRED_TIME);
+        cache.remove("evictor2");
+
+        // Assert
+        assert_eq!(
+            *removals.lock().unwrap(),
+            vec![
+                ("expired", "val", RemovalCause::Expired),
+                ("replaced", "old", RemovalCause::Replaced),
+                ("replaced", "new", RemovalCause::Capacity),
+                ("evictor2", "val", RemovalCause::Explicit),
+            ]
+        );
+    }
+
+    #[test]
+    fn given_a_cache_with_a_channel_removal_listener_when_it_is_cleared_then_every_entry_is_sent() {
+        // Arrange
+        let (sender, mut receiver) = unbounded_channel();
+        let mut cache = TtlCache::builder().removal_listener(sender).build();
+        cache.insert("key1", "val1", *UNEXPIRED_TIME);
+        cache.insert("key2", "val2", *EXPIRED_TIME);
+
+        // Act
+        cache.clear();
+
+        // Assert
+        let mut removals = Vec::new();
+        while let Ok(removal) = receiver.try_recv() {
+            removals.push(removal);
+        }
+        removals.sort_by_key(|(key, _val, _cause)| *key);
+        assert_eq!(
+            removals,
+            vec![
+                ("key1", "val1", RemovalCause::Explicit),
+                ("key2", "val2", RemovalCause::Explicit),
+            ]
+        );
+    }
+
+    #[test]
+    fn given_a_cache_with_a_grace_period_when_an_entry_expires_then_it_can_be_read_as_stale_until_the_grace_period_passes(
+    ) {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .grace_period(Duration::from_secs(60))
+            .build();
+        cache.insert("fresh", "val", *UNEXPIRED_TIME);
+        cache.insert("stale", "val", *EXPIRED_TIME);
+        cache.insert("purged", "val", Instant::now() - Duration::from_secs(120));
+
+        // Act
+        cache.purge_expired();
+
+        // Assert
+        assert_eq!(cache.get_stale("fresh"), Some((&"val", Freshness::Fresh)));
+        assert!(cache.get_stale("stale").unwrap().1.is_stale());
+        assert_eq!(cache.get("stale"), None);
+        assert_eq!(cache.get_stale("purged"), None);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn given_a_cache_that_records_stats_when_it_is_used_then_every_operation_is_counted() {
+        // Arrange
+        let mut cache = TtlCache::builder()
+            .capacity_limit(2)
+            .eviction_policy(Lru::new())
+            .record_stats()
+            .build();
+
+        // Act
+        cache.insert("key1", "val", *UNEXPIRED_TIME);
+        cache.insert("key1", "val", *UNEXPIRED_TIME);
+        cache.insert("key2", "val", *EXPIRED_TIME);
+        cache.get("key1");
+        cache.get("key2");
+        cache.get("key3");
+        cache.purge_expired();
+        cache.insert("key2", "val", *UNEXPIRED_TIME);
+        cache.insert("key3", "val", *UNEXPIRED_TIME);
+        if let Entry::Occupied(mut e) = cache.entry("key3") {
+            e.insert("new");
+        }
+
+        // Assert
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.expired_misses, 1);
+        assert_eq!(stats.inserts, 6);
+        assert_eq!(stats.overwrites, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.purges, 1);
+        assert_eq!(stats.purged, 1);
+        assert_eq!(stats.hit_ratio(), 1.0 / 3.0);
+        assert!(TtlCache::<&str, &str>::new().stats().is_none());
+    }
+
+    #[test]
+    fn given_a_cache_with_a_mock_clock_when_the_clock_passes_the_ttl_then_the_entry_expires_and_is_purged(
+    ) {
+        // Arrange
+        let clock = MockClock::new();
+        let mut cache = TtlCache::builder().clock(clock.clone()).build();
+        cache.insert_with_ttl("key", "val", Duration::from_secs(60));
+
+        // Act
+        clock.advance(Duration::from_secs(59));
+        let before_ttl = cache.get("key").copied();
+        clock.advance(Duration::from_secs(1));
+        let after_ttl = cache.get("key").copied();
+        cache.purge_expired();
+
+        // Assert
+        assert_eq!(before_ttl, Some("val"));
+        assert_eq!(after_ttl, None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn given_an_entry_inserted_at_unix_secs_when_the_wall_clock_passes_them_then_it_expires() {
+        // Arrange
+        let clock = MockClock::new();
+        let mut cache = TtlCache::builder().clock(clock.clone()).build();
+        let now_secs = clock
+            .system_now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Act
+        cache.insert_at_unix_secs("key", "val", now_secs + 60);
+        cache.insert_at_unix_secs("expired", "val", now_secs - 60);
+        clock.advance(Duration::from_secs(58));
+        let before_exp = cache.get("key").copied();
+        clock.advance(Duration::from_secs(2));
+        let after_exp = cache.get("key").copied();
+
+        // Assert
+        assert_eq!(before_exp, Some("val"));
+        assert_eq!(after_exp, None);
+        assert_eq!(cache.get("expired"), None);
+    }
+
+    #[test]
+    fn given_a_clock_skew_policy_when_inserting_at_a_system_time_then_the_expiration_is_adjusted() {
+        // Arrange
+        let clock = MockClock::new();
+        let mut early = TtlCache::builder()
+            .clock(clock.clone())
+            .clock_skew(ClockSkew::ExpireEarly(Duration::from_secs(30)))
+            .build();
+        let mut leeway = TtlCache::builder()
+            .clock(clock.clone())
+            .clock_skew(ClockSkew::Leeway(Duration::from_secs(30)))
+            .build();
+        let expires_at = clock.system_now() + Duration::from_secs(60);
+
+        // Act
+        early.insert_with_expiration("key", "val", expires_at);
+        leeway.insert_with_expiration("key", "val", expires_at);
+
+        // Assert
+        let now = clock.now();
+        assert_eq!(
+            early.get_value_and_expiration("key").unwrap().1,
+            now + Duration::from_secs(30)
+        );
+        assert_eq!(
+            leeway.get_value_and_expiration("key").unwrap().1,
+            now + Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "default TTL")]
+    fn given_a_cache_without_a_default_ttl_when_adding_a_default_entry_then_it_panics() {
+        // Arrange
+        let mut cache = TtlCache::new();
+
+        // Act
+        cache.insert_default("key", "val");
+    }
 }
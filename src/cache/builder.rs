@@ -0,0 +1,274 @@
+//! Configuration for new cache instances.
+use std::{hash::Hash, marker::PhantomData, sync::Mutex, time::Duration};
+
+#[cfg(feature = "metrics")]
+use super::metrics::CacheMetrics;
+use super::{Bound, EntryTable, ExpiryIndex, RemovalListener, StatsCounter, TtlCache, MAX_TTL};
+#[cfg(feature = "wal")]
+use crate::wal::{Journal, WriteAheadLog};
+use crate::{
+    clock::{Clock, ClockSkew, RealClock},
+    eviction::{EvictionPolicy, Unbounded, Weigher},
+};
+
+/// Builds a [`TtlCache`] with non-default settings.
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use ttl_cache_with_purging::{cache::TtlCache, eviction::Lru};
+///
+/// let mut cache = TtlCache::builder()
+///     .default_ttl(Duration::from_secs(60))
+///     .capacity_limit(1000)
+///     .eviction_policy(Lru::new())
+///     .build();
+/// cache.insert_default("key", "val");
+/// ```
+pub struct TtlCacheBuilder<K, V, P = Unbounded, C = RealClock> {
+    default_ttl: Option<Duration>,
+    time_to_idle: Option<Duration>,
+    grace_period: Option<Duration>,
+    clock_skew: ClockSkew,
+    capacity_limit: Option<usize>,
+    max_weight: Option<u64>,
+    weigher: Option<Box<dyn Weigher<K, V> + Send + Sync>>,
+    listener: Option<Box<dyn RemovalListener<K, V> + Send + Sync>>,
+    #[cfg(feature = "wal")]
+    journal: Option<Box<dyn Journal<K, V> + Send + Sync>>,
+    record_stats: bool,
+    #[cfg(feature = "metrics")]
+    metrics_name: Option<String>,
+    policy: P,
+    clock: C,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TtlCacheBuilder<K, V> {
+    pub(super) fn new() -> Self {
+        TtlCacheBuilder {
+            default_ttl: None,
+            time_to_idle: None,
+            grace_period: None,
+            clock_skew: ClockSkew::Exact,
+            capacity_limit: None,
+            max_weight: None,
+            weigher: None,
+            listener: None,
+            #[cfg(feature = "wal")]
+            journal: None,
+            record_stats: false,
+            #[cfg(feature = "metrics")]
+            metrics_name: None,
+            policy: Unbounded,
+            clock: RealClock,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, P, C> TtlCacheBuilder<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Sets the TTL used by [`TtlCache::insert_default`].
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Gives every entry a sliding expiration: entries expire once they have gone unread
+    /// for `time_to_idle`, in addition to their own expiration.
+    pub fn time_to_idle(mut self, time_to_idle: Duration) -> Self {
+        self.time_to_idle = Some(time_to_idle);
+        self
+    }
+
+    /// Keeps expired entries for `grace_period` after they expire, so that they can still be
+    /// read through [`TtlCache::get_stale`], for example while their source is unavailable.
+    ///
+    /// Entries in their grace period still count towards the cache's bounds, but are otherwise
+    /// treated as expired, and are evicted before any unexpired entry when the cache is full.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = Some(grace_period);
+        self
+    }
+
+    /// Sets how wall-clock expirations, as inserted with [`TtlCache::insert_at_system_time`],
+    /// allow for the clock of whoever set them disagreeing with this one. By default they are
+    /// trusted as given.
+    pub fn clock_skew(mut self, clock_skew: ClockSkew) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// Bounds the cache to at most `capacity_limit` entries.
+    ///
+    /// Once full, expired entries are evicted first, and then the entry chosen by the
+    /// eviction policy. The default policy is [`Unbounded`], which never evicts live entries,
+    /// so this is usually paired with [`TtlCacheBuilder::eviction_policy`].
+    pub fn capacity_limit(mut self, capacity_limit: usize) -> Self {
+        self.capacity_limit = Some(capacity_limit);
+        self
+    }
+
+    /// Bounds the cache to a total weight of at most `max_weight`, as computed by the
+    /// cache's [`Weigher`].
+    ///
+    /// Entries are evicted the same way as for [`TtlCacheBuilder::capacity_limit`]. An entry
+    /// that is heavier than `max_weight` on its own evicts everything the policy is willing to
+    /// evict, and is then inserted anyway.
+    pub fn max_weight(mut self, max_weight: u64) -> Self {
+        self.max_weight = Some(max_weight);
+        self
+    }
+
+    /// Sets how each entry is weighed. Without a weigher, every entry weighs 1.
+    pub fn weigher<W>(mut self, weigher: W) -> Self
+    where
+        W: Weigher<K, V> + Send + Sync + 'static,
+    {
+        self.weigher = Some(Box::new(weigher));
+        self
+    }
+
+    /// Sets a listener that is notified whenever an entry leaves the cache.
+    pub fn removal_listener<L>(mut self, listener: L) -> Self
+    where
+        L: RemovalListener<K, V> + Send + Sync + 'static,
+    {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Appends every change to the cache to a write-ahead log, so that the cache can be rebuilt
+    /// with [`WriteAheadLog::replay`] after a crash.
+    ///
+    /// Inserts, removals, evictions and purges are logged, including changes made through
+    /// [`TtlCache::entry`], but changes made to values in place, through `get_mut` or
+    /// `into_mut`, are not.
+    #[cfg(feature = "wal")]
+    pub fn write_ahead_log(mut self, wal: WriteAheadLog) -> Self
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        self.journal = Some(Box::new(wal));
+        self
+    }
+
+    /// Records statistics, which can then be read through [`TtlCache::stats`].
+    pub fn record_stats(mut self) -> Self {
+        self.record_stats = true;
+        self
+    }
+
+    /// Reports statistics through the [`metrics`](https://docs.rs/metrics) facade, labeled with
+    /// `cache` set to `name`. This implies [`TtlCacheBuilder::record_stats`].
+    ///
+    /// Counters are updated as the cache is used. The `ttl_cache_size` and
+    /// `ttl_cache_hit_ratio` gauges are updated after every purge, along with the
+    /// `ttl_cache_purge_duration_seconds` histogram.
+    ///
+    /// Metrics are registered with the recorder that is installed when the cache is built.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, name: impl Into<String>) -> Self {
+        self.metrics_name = Some(name.into());
+        self
+    }
+
+    /// Sets the policy that chooses which live entry to evict once a bounded cache is full.
+    pub fn eviction_policy<P2>(self, policy: P2) -> TtlCacheBuilder<K, V, P2, C>
+    where
+        P2: EvictionPolicy<K>,
+    {
+        TtlCacheBuilder {
+            default_ttl: self.default_ttl,
+            time_to_idle: self.time_to_idle,
+            grace_period: self.grace_period,
+            clock_skew: self.clock_skew,
+            capacity_limit: self.capacity_limit,
+            max_weight: self.max_weight,
+            weigher: self.weigher,
+            listener: self.listener,
+            #[cfg(feature = "wal")]
+            journal: self.journal,
+            record_stats: self.record_stats,
+            #[cfg(feature = "metrics")]
+            metrics_name: self.metrics_name,
+            policy,
+            clock: self.clock,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the clock the cache reads the current time from, such as a
+    /// [`MockClock`](crate::clock::MockClock) in tests.
+    pub fn clock<C2>(self, clock: C2) -> TtlCacheBuilder<K, V, P, C2>
+    where
+        C2: Clock,
+    {
+        TtlCacheBuilder {
+            default_ttl: self.default_ttl,
+            time_to_idle: self.time_to_idle,
+            grace_period: self.grace_period,
+            clock_skew: self.clock_skew,
+            capacity_limit: self.capacity_limit,
+            max_weight: self.max_weight,
+            weigher: self.weigher,
+            listener: self.listener,
+            #[cfg(feature = "wal")]
+            journal: self.journal,
+            record_stats: self.record_stats,
+            #[cfg(feature = "metrics")]
+            metrics_name: self.metrics_name,
+            policy: self.policy,
+            clock,
+            _marker: PhantomData,
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn stats_counter(&self) -> Option<Box<StatsCounter>> {
+        self.record_stats.then(|| Box::new(StatsCounter::default()))
+    }
+
+    #[cfg(feature = "metrics")]
+    fn stats_counter(&self) -> Option<Box<StatsCounter>> {
+        match &self.metrics_name {
+            Some(name) => Some(Box::new(StatsCounter::with_metrics(CacheMetrics::new(
+                name,
+            )))),
+            None => self.record_stats.then(|| Box::new(StatsCounter::default())),
+        }
+    }
+
+    /// Creates the configured cache.
+    pub fn build(self) -> TtlCache<K, V, P, C> {
+        let bounded = self.capacity_limit.is_some() || self.max_weight.is_some();
+        let stats = self.stats_counter();
+        TtlCache {
+            map: EntryTable::default(),
+            expirations: ExpiryIndex::default(),
+            next_seq: 0,
+            default_ttl: self.default_ttl,
+            time_to_idle: self.time_to_idle,
+            grace_period: self.grace_period.unwrap_or_default().min(MAX_TTL),
+            clock_skew: self.clock_skew,
+            weigher: self.weigher,
+            total_weight: 0,
+            listener: self.listener,
+            #[cfg(feature = "wal")]
+            journal: self.journal,
+            stats,
+            bound: bounded.then(|| Bound {
+                capacity_limit: self.capacity_limit,
+                max_weight: self.max_weight,
+                policy: Mutex::new(self.policy),
+            }),
+            clock: self.clock,
+        }
+    }
+}
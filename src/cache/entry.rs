@@ -0,0 +1,277 @@
+//! In-place manipulation of a single cache entry, modeled after
+//! [`std::collections::hash_map::Entry`].
+use std::{hash::Hash, time::Duration};
+
+use tokio::time::Instant;
+
+use super::{RemovalCause, TtlCache};
+use crate::{
+    clock::{Clock, RealClock},
+    eviction::{EvictionPolicy, Unbounded},
+};
+
+/// A view into a single key of a [`TtlCache`], as returned by [`TtlCache::entry`].
+///
+/// Expired entries are reported separately from vacant ones so their slot and stale value can
+/// be reused, but every convenience method on `Entry` treats them as vacant.
+pub enum Entry<'a, K, V, P = Unbounded, C = RealClock> {
+    /// The key is present and unexpired.
+    Occupied(OccupiedEntry<'a, K, V, P, C>),
+    /// The key is not present.
+    Vacant(VacantEntry<'a, K, V, P, C>),
+    /// The key is present, but has expired and is waiting to be purged.
+    Expired(ExpiredEntry<'a, K, V, P, C>),
+}
+
+/// An unexpired entry in the cache.
+pub struct OccupiedEntry<'a, K, V, P = Unbounded, C = RealClock> {
+    cache: &'a mut TtlCache<K, V, P, C>,
+    key: K,
+}
+
+/// A key that is not present in the cache.
+pub struct VacantEntry<'a, K, V, P = Unbounded, C = RealClock> {
+    cache: &'a mut TtlCache<K, V, P, C>,
+    key: K,
+}
+
+/// An entry that has expired but has not yet been purged from the cache.
+pub struct ExpiredEntry<'a, K, V, P = Unbounded, C = RealClock> {
+    cache: &'a mut TtlCache<K, V, P, C>,
+    key: K,
+}
+
+impl<'a, K, V, P, C> Entry<'a, K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    pub(super) fn new(cache: &'a mut TtlCache<K, V, P, C>, key: K) -> Self {
+        match cache.map.get(&key).map(|e| e.is_live(cache.clock.now())) {
+            Some(true) => Entry::Occupied(OccupiedEntry { cache, key }),
+            Some(false) => Entry::Expired(ExpiredEntry { cache, key }),
+            None => Entry::Vacant(VacantEntry { cache, key }),
+        }
+    }
+
+    /// The key of this entry.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+            Entry::Expired(e) => e.key(),
+        }
+    }
+
+    /// Ensures an unexpired value is in the entry by inserting the given value if it is vacant
+    /// or expired, and returns a mutable reference to the value.
+    pub fn or_insert(self, val: V, expires_at: Instant) -> &'a mut V {
+        self.or_insert_with(|| (val, expires_at))
+    }
+
+    /// Ensures an unexpired value is in the entry by inserting the value and expiration returned
+    /// by `default` if it is vacant or expired, and returns a mutable reference to the value.
+    pub fn or_insert_with<F>(self, default: F) -> &'a mut V
+    where
+        F: FnOnce() -> (V, Instant),
+    {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let (val, expires_at) = default();
+                e.insert(val, expires_at)
+            }
+            Entry::Expired(e) => {
+                let (val, expires_at) = default();
+                e.insert(val, expires_at)
+            }
+        }
+    }
+
+    /// Like [`Entry::or_insert`], but expires the inserted value after `ttl`.
+    pub fn or_insert_with_ttl(self, val: V, ttl: Duration) -> &'a mut V {
+        let expires_at = match &self {
+            Entry::Occupied(e) => e.cache.expiration_after(ttl),
+            Entry::Vacant(e) => e.cache.expiration_after(ttl),
+            Entry::Expired(e) => e.cache.expiration_after(ttl),
+        };
+        self.or_insert(val, expires_at)
+    }
+
+    /// Provides in-place mutable access to an unexpired entry before any potential inserts.
+    pub fn and_modify<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        match self {
+            Entry::Occupied(mut e) => {
+                f(e.get_mut());
+                Entry::Occupied(e)
+            }
+            entry => entry,
+        }
+    }
+}
+
+impl<'a, K, V, P, C> OccupiedEntry<'a, K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// The key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The value of this entry.
+    pub fn get(&self) -> &V {
+        &self.cache.map[&self.key].val
+    }
+
+    /// A mutable reference to the value of this entry.
+    ///
+    /// Changes made through this reference are not re-weighed. Use
+    /// [`OccupiedEntry::insert`] to replace a value whose weight may differ.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.cache.entry_mut(&self.key).val
+    }
+
+    /// Converts the entry into a mutable reference to its value, with the lifetime of the cache.
+    ///
+    /// Changes made through this reference are not re-weighed.
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.cache.entry_mut(&self.key).val
+    }
+
+    /// When this entry expires, taking any time-to-idle into account.
+    pub fn expires_at(&self) -> Instant {
+        self.cache.map[&self.key].deadline()
+    }
+
+    /// Changes when this entry expires, returning the previous expiration.
+    ///
+    /// For entries with a time-to-idle, this changes the maximum lifetime.
+    pub fn set_expires_at(&mut self, expires_at: Instant) -> Instant {
+        let entry = self.cache.entry_mut(&self.key);
+        entry.system_expires_at = None;
+        let previous = std::mem::replace(&mut entry.expires_at, expires_at);
+        self.cache.reschedule(&self.key);
+        self.cache.log_insert(&self.key);
+        previous
+    }
+
+    /// Changes this entry to expire after `ttl`, returning the previous expiration.
+    pub fn set_ttl(&mut self, ttl: Duration) -> Instant {
+        self.set_expires_at(self.cache.expiration_after(ttl))
+    }
+
+    /// Replaces the value of this entry, keeping its expiration, and returns the old value.
+    pub fn insert(&mut self, val: V) -> V {
+        self.cache.replace_val(&self.key, val)
+    }
+
+    /// Removes this entry from the cache, returning its value.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Removes this entry from the cache, returning its key and value.
+    pub fn remove_entry(self) -> (K, V) {
+        let (key, entry) = self
+            .cache
+            .remove_key(&self.key, RemovalCause::Explicit)
+            .expect("occupied entries are present in the cache");
+        (key, entry.val)
+    }
+}
+
+impl<'a, K, V, P, C> VacantEntry<'a, K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// The key that would be used when inserting a value through this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Takes ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts a value that will expire at the specified time, returning a mutable reference
+    /// to it.
+    pub fn insert(self, val: V, expires_at: Instant) -> &'a mut V {
+        self.cache.insert_and_get_mut(self.key, val, expires_at)
+    }
+
+    /// Inserts a value that will expire after `ttl`, returning a mutable reference to it.
+    pub fn insert_with_ttl(self, val: V, ttl: Duration) -> &'a mut V {
+        let expires_at = self.cache.expiration_after(ttl);
+        self.insert(val, expires_at)
+    }
+}
+
+impl<'a, K, V, P, C> ExpiredEntry<'a, K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// The key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The expired value that is still held by the cache.
+    pub fn stale_value(&self) -> &V {
+        &self.cache.map[&self.key].val
+    }
+
+    /// When this entry expired, taking any time-to-idle into account.
+    pub fn expired_at(&self) -> Instant {
+        self.cache.map[&self.key].deadline()
+    }
+
+    /// Reuses the slot of this entry for a new value that will expire at the specified time,
+    /// returning a mutable reference to it.
+    pub fn insert(self, val: V, expires_at: Instant) -> &'a mut V {
+        self.cache.insert_and_get_mut(self.key, val, expires_at)
+    }
+
+    /// Reuses the slot of this entry for a new value that will expire after `ttl`, returning a
+    /// mutable reference to it.
+    pub fn insert_with_ttl(self, val: V, ttl: Duration) -> &'a mut V {
+        let expires_at = self.cache.expiration_after(ttl);
+        self.insert(val, expires_at)
+    }
+
+    /// Brings the stale value back to life until the specified time, converting this into an
+    /// occupied entry. Any time-to-idle restarts from now.
+    pub fn revive(self, expires_at: Instant) -> OccupiedEntry<'a, K, V, P, C> {
+        let now = self.cache.clock.now();
+        let entry = self.cache.entry_mut(&self.key);
+        entry.expires_at = expires_at;
+        entry.system_expires_at = None;
+        entry.touch(now);
+        self.cache.reschedule(&self.key);
+        self.cache.log_insert(&self.key);
+        OccupiedEntry {
+            cache: self.cache,
+            key: self.key,
+        }
+    }
+
+    /// Removes the expired entry from the cache, returning its key and stale value.
+    pub fn remove_entry(self) -> (K, V) {
+        let (key, entry) = self
+            .cache
+            .remove_key(&self.key, RemovalCause::Explicit)
+            .expect("expired entries are present in the cache");
+        (key, entry.val)
+    }
+}
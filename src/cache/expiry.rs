@@ -0,0 +1,92 @@
+//! An index of when entries expire, so that purging only visits expired entries.
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use tokio::time::Instant;
+
+/// A min-heap of entry deadlines.
+///
+/// Entries are never removed from the heap directly. Instead, every scheduled deadline is
+/// tagged with the sequence number of the entry it was scheduled for, and deadlines whose
+/// entry has since been removed, replaced or rescheduled are skipped when they come due.
+/// Deadlines hold the hash of their entry's key rather than the key itself, which is enough
+/// to find the entry again along with its sequence number.
+#[derive(Default)]
+pub(super) struct ExpiryIndex {
+    heap: BinaryHeap<Reverse<Scheduled>>,
+}
+
+struct Scheduled {
+    deadline: Instant,
+    seq: u64,
+    hash: u64,
+}
+
+impl ExpiryIndex {
+    pub(super) fn schedule(&mut self, hash: u64, deadline: Instant, seq: u64) {
+        self.heap.push(Reverse(Scheduled {
+            deadline,
+            seq,
+            hash,
+        }));
+    }
+
+    /// Pops the soonest deadline if it is due by `now`, returning its key's hash and sequence
+    /// number.
+    pub(super) fn pop_due(&mut self, now: Instant) -> Option<(u64, u64)> {
+        if !self.has_due(now) {
+            return None;
+        }
+        self.heap.pop().map(|Reverse(s)| (s.hash, s.seq))
+    }
+
+    /// Whether the soonest deadline is due by `now`.
+    pub(super) fn has_due(&self, now: Instant) -> bool {
+        self.heap.peek().is_some_and(|s| s.0.deadline <= now)
+    }
+
+    /// Whether enough deadlines have gone stale, compared to the number of live entries,
+    /// that the index should be rebuilt.
+    pub(super) fn needs_rebuild(&self, entries: usize) -> bool {
+        self.heap.len() > entries.saturating_mul(2).max(64)
+    }
+
+    /// Replaces every scheduled deadline, dropping the stale ones.
+    pub(super) fn rebuild(&mut self, scheduled: impl Iterator<Item = (u64, Instant, u64)>) {
+        self.heap = scheduled
+            .map(|(hash, deadline, seq)| {
+                Reverse(Scheduled {
+                    deadline,
+                    seq,
+                    hash,
+                })
+            })
+            .collect();
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.heap.clear();
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
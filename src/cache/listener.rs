@@ -0,0 +1,49 @@
+//! Notifications of entries leaving the cache.
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+
+use super::RemovalCause;
+
+/// Notified whenever an entry leaves a [`TtlCache`](super::TtlCache).
+///
+/// Listeners are called synchronously while the cache is borrowed mutably, and so while any
+/// lock around it is held. Slow work, such as closing connections, should be handed off, for
+/// example through a channel: [`UnboundedSender`] and [`Sender`] are listeners that send a
+/// clone of each removed entry.
+///
+/// Listeners are not notified of entries that are still cached when the cache is dropped.
+pub trait RemovalListener<K, V> {
+    /// Called with an entry that was removed from the cache, and why.
+    fn on_removal(&self, key: &K, val: &V, cause: RemovalCause);
+}
+
+impl<K, V, F> RemovalListener<K, V> for F
+where
+    F: Fn(&K, &V, RemovalCause),
+{
+    fn on_removal(&self, key: &K, val: &V, cause: RemovalCause) {
+        self(key, val, cause)
+    }
+}
+
+/// Sends every removal, ignoring removals once the receiver has been dropped.
+impl<K, V> RemovalListener<K, V> for UnboundedSender<(K, V, RemovalCause)>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn on_removal(&self, key: &K, val: &V, cause: RemovalCause) {
+        let _ = self.send((key.clone(), val.clone(), cause));
+    }
+}
+
+/// Sends removals without waiting, dropping them while the channel is full or once the
+/// receiver has been dropped.
+impl<K, V> RemovalListener<K, V> for Sender<(K, V, RemovalCause)>
+where
+    K: Clone,
+    V: Clone,
+{
+    fn on_removal(&self, key: &K, val: &V, cause: RemovalCause) {
+        let _ = self.try_send((key.clone(), val.clone(), cause));
+    }
+}
@@ -0,0 +1,213 @@
+//! Reporting of cache statistics through the [`metrics`] facade.
+use std::time::Duration;
+
+use metrics::{counter, gauge, histogram, Counter, Gauge, Histogram};
+
+use super::CacheStats;
+
+/// Handles to every metric of a single cache, labeled with its name.
+///
+/// Handles are registered once, when the cache is built, so recording doesn't need to look
+/// them up.
+pub(super) struct CacheMetrics {
+    hits: Counter,
+    misses: Counter,
+    expired_misses: Counter,
+    inserts: Counter,
+    overwrites: Counter,
+    evictions: Counter,
+    purged: Counter,
+    purge_duration: Histogram,
+    size: Gauge,
+    hit_ratio: Gauge,
+}
+
+impl CacheMetrics {
+    pub(super) fn new(name: &str) -> Self {
+        let labels = [("cache", name.to_owned())];
+        CacheMetrics {
+            hits: counter!("ttl_cache_hits_total", &labels),
+            misses: counter!("ttl_cache_misses_total", &labels),
+            expired_misses: counter!("ttl_cache_expired_misses_total", &labels),
+            inserts: counter!("ttl_cache_inserts_total", &labels),
+            overwrites: counter!("ttl_cache_overwrites_total", &labels),
+            evictions: counter!("ttl_cache_evictions_total", &labels),
+            purged: counter!("ttl_cache_purged_total", &labels),
+            purge_duration: histogram!("ttl_cache_purge_duration_seconds", &labels),
+            size: gauge!("ttl_cache_size", &labels),
+            hit_ratio: gauge!("ttl_cache_hit_ratio", &labels),
+        }
+    }
+
+    pub(super) fn record_hit(&self) {
+        self.hits.increment(1);
+    }
+
+    pub(super) fn record_miss(&self, expired: bool) {
+        self.misses.increment(1);
+        if expired {
+            self.expired_misses.increment(1);
+        }
+    }
+
+    pub(super) fn record_insert(&self, overwrite: bool) {
+        self.inserts.increment(1);
+        if overwrite {
+            self.overwrites.increment(1);
+        }
+    }
+
+    pub(super) fn record_eviction(&self) {
+        self.evictions.increment(1);
+    }
+
+    /// Records a purge, and updates the gauges, which are only refreshed by purges.
+    pub(super) fn record_purge(
+        &self,
+        purged: u64,
+        duration: Duration,
+        len: usize,
+        stats: CacheStats,
+    ) {
+        self.purged.increment(purged);
+        self.purge_duration.record(duration);
+        self.size.set(len as f64);
+        self.hit_ratio.set(stats.hit_ratio());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use metrics::{
+        with_local_recorder, Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key,
+        KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+
+    use crate::cache::{Purgeable, TtlCache};
+
+    /// Records the latest value of every metric, by name and `cache` label.
+    #[derive(Default)]
+    struct TestRecorder {
+        handles: Mutex<HashMap<(String, String), Arc<TestHandle>>>,
+    }
+
+    /// Counters and gauges store their value, and histograms their number of records.
+    #[derive(Default)]
+    struct TestHandle(AtomicU64);
+
+    impl TestRecorder {
+        fn handle(&self, key: &Key) -> Arc<TestHandle> {
+            let cache = key
+                .labels()
+                .find(|label| label.key() == "cache")
+                .map(|label| label.value().to_owned())
+                .unwrap_or_default();
+            let mut handles = self.handles.lock().unwrap();
+            handles
+                .entry((key.name().to_owned(), cache))
+                .or_default()
+                .clone()
+        }
+
+        fn value(&self, name: &str, cache: &str) -> u64 {
+            self.handles.lock().unwrap()[&(name.to_owned(), cache.to_owned())]
+                .0
+                .load(Ordering::SeqCst)
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.handle(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.handle(key))
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.handle(key))
+        }
+    }
+
+    impl CounterFn for TestHandle {
+        fn increment(&self, value: u64) {
+            self.0.fetch_add(value, Ordering::SeqCst);
+        }
+
+        fn absolute(&self, value: u64) {
+            self.0.store(value, Ordering::SeqCst);
+        }
+    }
+
+    impl GaugeFn for TestHandle {
+        fn increment(&self, value: f64) {
+            self.0.fetch_add(value as u64, Ordering::SeqCst);
+        }
+
+        fn decrement(&self, value: f64) {
+            self.0.fetch_sub(value as u64, Ordering::SeqCst);
+        }
+
+        fn set(&self, value: f64) {
+            self.0.store(value as u64, Ordering::SeqCst);
+        }
+    }
+
+    impl HistogramFn for TestHandle {
+        fn record(&self, _value: f64) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn given_caches_with_metrics_names_when_they_are_used_then_metrics_are_reported_per_cache() {
+        // Arrange
+        let recorder = TestRecorder::default();
+        let (mut tenant_a, tenant_b) = with_local_recorder(&recorder, || {
+            (
+                TtlCache::builder().metrics("tenant-a").build(),
+                TtlCache::<&str, &str>::builder()
+                    .metrics("tenant-b")
+                    .build(),
+            )
+        });
+
+        // Act
+        tenant_a.insert_with_ttl("key1", "val", Duration::from_secs(60));
+        tenant_a.insert_with_ttl("key2", "val", Duration::ZERO);
+        tenant_a.get("key1");
+        tenant_a.get("key2");
+        tenant_a.purge_expired();
+        tenant_b.get("key1");
+
+        // Assert
+        assert_eq!(recorder.value("ttl_cache_inserts_total", "tenant-a"), 2);
+        assert_eq!(recorder.value("ttl_cache_hits_total", "tenant-a"), 1);
+        assert_eq!(recorder.value("ttl_cache_misses_total", "tenant-a"), 1);
+        assert_eq!(recorder.value("ttl_cache_purged_total", "tenant-a"), 1);
+        assert_eq!(
+            recorder.value("ttl_cache_purge_duration_seconds", "tenant-a"),
+            1
+        );
+        assert_eq!(recorder.value("ttl_cache_size", "tenant-a"), 1);
+        assert_eq!(recorder.value("ttl_cache_hits_total", "tenant-b"), 0);
+        assert_eq!(recorder.value("ttl_cache_misses_total", "tenant-b"), 1);
+        assert!(tenant_b.stats().is_some());
+    }
+}
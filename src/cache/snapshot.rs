@@ -0,0 +1,268 @@
+//! Serializable copies of a cache's contents, for warm restarts.
+use std::{
+    hash::Hash,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::{CacheEntry, TtlCache};
+use crate::{clock::Clock, eviction::EvictionPolicy};
+
+/// The unexpired entries of a [`TtlCache`], as taken by [`TtlCache::snapshot`], which can be
+/// serialized and later [restored](TtlCache::restore), for example by the next run of the
+/// same process.
+///
+/// `Instant`s can't be serialized, so each entry is stored with how long it had left when the
+/// snapshot was taken, along with the wall-clock time the snapshot was taken at. Entries that
+/// were inserted with a wall-clock expiration keep that expiration instead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot<K, V> {
+    taken_at: SystemTime,
+    entries: Vec<SnapshotEntry<K, V>>,
+}
+
+/// A single entry, with its expiration stored in a form that survives a restart.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct SnapshotEntry<K, V> {
+    key: K,
+    val: V,
+    expires: SnapshotExpiration,
+    time_to_idle: Option<Duration>,
+    /// How long an entry with a time-to-idle had left before going idle. Missing from
+    /// snapshots taken before it was added, whose idle timeouts restart when restored.
+    #[serde(default)]
+    idle_remaining: Option<Duration>,
+}
+
+/// When a snapshotted entry expires.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum SnapshotExpiration {
+    /// The entry expires this long after the snapshot was taken.
+    After(Duration),
+    /// The entry expires at this wall-clock time, before adjusting for clock skew.
+    AtSystemTime(SystemTime),
+}
+
+impl<K, V> Snapshot<K, V> {
+    /// The wall-clock time the snapshot was taken at.
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+
+    /// The number of entries in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the snapshot has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<'a, K, V> SnapshotEntry<&'a K, &'a V> {
+    /// Borrows an entry of the cache, as of `now`.
+    pub(super) fn borrowed(key: &'a K, entry: &'a CacheEntry<V>, now: Instant) -> Self {
+        SnapshotEntry {
+            key,
+            val: &entry.val,
+            expires: match entry.system_expires_at {
+                Some(expires_at) => SnapshotExpiration::AtSystemTime(expires_at),
+                None => SnapshotExpiration::After(entry.expires_at.saturating_duration_since(now)),
+            },
+            time_to_idle: entry.idle.as_ref().map(|idle| idle.time_to_idle),
+            idle_remaining: entry
+                .idle
+                .as_ref()
+                .map(|_| entry.deadline().saturating_duration_since(now)),
+        }
+    }
+
+    fn cloned(&self) -> SnapshotEntry<K, V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        SnapshotEntry {
+            key: self.key.clone(),
+            val: self.val.clone(),
+            expires: self.expires,
+            time_to_idle: self.time_to_idle,
+            idle_remaining: self.idle_remaining,
+        }
+    }
+}
+
+impl<K, V, P, C> TtlCache<K, V, P, C>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Copies the unexpired entries of the cache into a [`Snapshot`], without touching them.
+    ///
+    /// Entries with a time-to-idle keep it, along with how long they had left before going
+    /// idle, which keeps counting down until they are restored.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        let borrowed = self.borrowed_snapshot();
+        Snapshot {
+            taken_at: borrowed.taken_at,
+            entries: borrowed.entries.iter().map(SnapshotEntry::cloned).collect(),
+        }
+    }
+}
+
+impl<K, V, P, C> TtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Like [`TtlCache::snapshot`], but borrows the entries instead of copying them. This
+    /// serializes the same as the copied snapshot.
+    pub(crate) fn borrowed_snapshot(&self) -> Snapshot<&K, &V> {
+        let now = self.clock.now();
+        let entries = self
+            .map
+            .iter()
+            .filter(|(_k, e)| e.is_live(now))
+            .map(|(k, e)| SnapshotEntry::borrowed(k, e, now))
+            .collect();
+        Snapshot {
+            taken_at: self.clock.system_now(),
+            entries,
+        }
+    }
+
+    /// Inserts the entries of a snapshot, replacing any entries with the same keys, and
+    /// returns how many were inserted.
+    ///
+    /// Expirations keep counting down, by the wall clock, from when the snapshot was taken.
+    /// Wall-clock expirations are adjusted by this cache's
+    /// [`ClockSkew`](crate::clock::ClockSkew) policy. Entries that have expired since the
+    /// snapshot was taken are dropped.
+    pub fn restore(&mut self, snapshot: Snapshot<K, V>) -> usize {
+        let elapsed = self
+            .clock
+            .system_now()
+            .duration_since(snapshot.taken_at)
+            .unwrap_or_default();
+        snapshot
+            .entries
+            .into_iter()
+            .map(|entry| self.restore_entry(entry, elapsed))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// Inserts an entry that was saved `elapsed` ago, or returns its key without inserting it
+    /// if it has expired or gone idle since.
+    pub(crate) fn restore_entry(
+        &mut self,
+        entry: SnapshotEntry<K, V>,
+        elapsed: Duration,
+    ) -> Result<(), K> {
+        let (remaining, system_expires_at) = match entry.expires {
+            SnapshotExpiration::After(ttl) => (ttl.saturating_sub(elapsed), None),
+            SnapshotExpiration::AtSystemTime(expires_at) => {
+                (self.time_until_system_time(expires_at), Some(expires_at))
+            }
+        };
+        let idle_remaining = entry
+            .idle_remaining
+            .map(|idle_remaining| idle_remaining.saturating_sub(elapsed));
+        if remaining.is_zero() || idle_remaining.is_some_and(|idle| idle.is_zero()) {
+            return Err(entry.key);
+        }
+        let expires_at = self.expiration_after(remaining);
+        // Backdates the entry so that its idle timeout resumes where it left off, rather than
+        // restarting.
+        let now = self.clock.now();
+        let idle_for = entry
+            .time_to_idle
+            .zip(idle_remaining)
+            .map_or(Duration::ZERO, |(time_to_idle, idle)| {
+                time_to_idle.saturating_sub(idle)
+            });
+        let inserted_at = now.checked_sub(idle_for).unwrap_or(now);
+        let mut restored = CacheEntry::new(entry.val, inserted_at, expires_at, entry.time_to_idle);
+        restored.system_expires_at = system_expires_at;
+        self.insert_entry(entry.key, restored);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        cache::{Snapshot, TtlCache},
+        clock::{Clock, MockClock},
+    };
+
+    #[test]
+    fn given_a_serialized_snapshot_when_restoring_it_later_then_expirations_keep_counting_down() {
+        // Arrange
+        let clock = MockClock::new();
+        let mut cache = TtlCache::builder().clock(clock.clone()).build();
+        cache.insert_with_ttl("long".to_owned(), 1, Duration::from_secs(60));
+        cache.insert_with_ttl("short".to_owned(), 2, Duration::from_secs(10));
+        cache.insert_with_ttl("expired".to_owned(), 3, Duration::ZERO);
+        cache.insert_at_system_time(
+            "wall".to_owned(),
+            4,
+            clock.system_now() + Duration::from_secs(120),
+        );
+        let json = serde_json::to_string(&cache.snapshot()).unwrap();
+
+        // Act
+        clock.advance(Duration::from_secs(30));
+        let snapshot: Snapshot<String, u32> = serde_json::from_str(&json).unwrap();
+        let mut restored = TtlCache::builder().clock(clock.clone()).build();
+        let count = restored.restore(snapshot);
+
+        // Assert
+        let now = clock.now();
+        assert_eq!(count, 2);
+        assert_eq!(
+            restored.get_value_and_expiration("long"),
+            Some((&1, now + Duration::from_secs(30)))
+        );
+        assert_eq!(
+            restored.get_value_and_expiration("wall"),
+            Some((&4, now + Duration::from_secs(90)))
+        );
+        assert_eq!(restored.get("short"), None);
+        assert_eq!(restored.get("expired"), None);
+        assert_eq!(restored.len(), 2);
+    }
+
+    #[test]
+    fn given_a_snapshot_of_idle_entries_when_restoring_it_later_then_idle_timeouts_keep_counting_down(
+    ) {
+        // Arrange
+        let clock = MockClock::new();
+        let mut cache = TtlCache::builder().clock(clock.clone()).build();
+        cache.insert_with_idle_timeout("idle".to_owned(), 1, Duration::from_secs(60), None);
+        cache.insert_with_idle_timeout("read".to_owned(), 2, Duration::from_secs(60), None);
+        clock.advance(Duration::from_secs(40));
+        cache.get("read");
+        let json = serde_json::to_string(&cache.snapshot()).unwrap();
+
+        // Act
+        clock.advance(Duration::from_secs(30));
+        let snapshot: Snapshot<String, u32> = serde_json::from_str(&json).unwrap();
+        let mut restored = TtlCache::builder().clock(clock.clone()).build();
+        let count = restored.restore(snapshot);
+
+        // Assert
+        assert_eq!(count, 1);
+        assert_eq!(restored.len(), 1);
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(restored.get("read"), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! Counters of how well the cache is working.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use super::metrics::CacheMetrics;
+
+/// A snapshot of a cache's statistics, as returned by
+/// [`TtlCache::stats`](super::TtlCache::stats).
+///
+/// Counters start when the cache is built, and are never reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads that found an unexpired value.
+    pub hits: u64,
+    /// Reads that found no unexpired value, including `expired_misses`.
+    pub misses: u64,
+    /// Reads that found a value that had expired but had not been purged yet.
+    pub expired_misses: u64,
+    /// Values added to the cache, including `overwrites`.
+    pub inserts: u64,
+    /// Inserts that replaced an unexpired value.
+    pub overwrites: u64,
+    /// Unexpired entries evicted to keep a bounded cache within its limits.
+    pub evictions: u64,
+    /// Calls that purged expired entries, including bounded purges. Purges made to make room in
+    /// a bounded cache are only counted if they removed an entry.
+    pub purges: u64,
+    /// Entries removed by purges.
+    pub purged: u64,
+    /// The total time spent purging.
+    pub purge_duration: Duration,
+}
+
+impl CacheStats {
+    /// The fraction of reads that found an unexpired value, or `1.0` if there have been no
+    /// reads.
+    pub fn hit_ratio(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            1.0
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+
+    /// The average number of entries removed per purge, or `0.0` if there have been no
+    /// purges.
+    pub fn purged_per_purge(&self) -> f64 {
+        if self.purges == 0 {
+            0.0
+        } else {
+            self.purged as f64 / self.purges as f64
+        }
+    }
+}
+
+/// The work done by a single purge.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct PurgeCounts {
+    /// The number of due deadlines that were visited.
+    pub(super) scanned: u64,
+    /// The number of entries that were removed.
+    pub(super) removed: u64,
+    /// Whether there may be more to purge.
+    pub(super) more: bool,
+}
+
+/// The live counters behind [`CacheStats`], which also report to the `metrics` facade when
+/// the cache was built with a metrics name.
+///
+/// Reads only hold a shared reference to the cache, so every counter is atomic.
+#[derive(Default)]
+pub(super) struct StatsCounter {
+    #[cfg(feature = "metrics")]
+    metrics: Option<CacheMetrics>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired_misses: AtomicU64,
+    inserts: AtomicU64,
+    overwrites: AtomicU64,
+    evictions: AtomicU64,
+    purges: AtomicU64,
+    purged: AtomicU64,
+    purge_nanos: AtomicU64,
+}
+
+impl StatsCounter {
+    #[cfg(feature = "metrics")]
+    pub(super) fn with_metrics(metrics: CacheMetrics) -> Self {
+        StatsCounter {
+            metrics: Some(metrics),
+            ..StatsCounter::default()
+        }
+    }
+
+    pub(super) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_hit();
+        }
+    }
+
+    pub(super) fn record_miss(&self, expired: bool) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if expired {
+            self.expired_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_miss(expired);
+        }
+    }
+
+    pub(super) fn record_insert(&self, overwrite: bool) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        if overwrite {
+            self.overwrites.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_insert(overwrite);
+        }
+    }
+
+    pub(super) fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_eviction();
+        }
+    }
+
+    /// Records a purge that left `len` entries in the cache.
+    pub(super) fn record_purge(&self, purged: u64, duration: Duration, len: usize) {
+        self.purges.fetch_add(1, Ordering::Relaxed);
+        self.purged.fetch_add(purged, Ordering::Relaxed);
+        self.purge_nanos.fetch_add(
+            u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.record_purge(purged, duration, len, self.snapshot());
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = len;
+    }
+
+    pub(super) fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            expired_misses: self.expired_misses.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            overwrites: self.overwrites.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            purges: self.purges.load(Ordering::Relaxed),
+            purged: self.purged.load(Ordering::Relaxed),
+            purge_duration: Duration::from_nanos(self.purge_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! The cache's entries, in a hash table that can also be searched by hash.
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+    ops::Index,
+};
+
+use hashbrown::{hash_table, HashTable};
+
+use super::CacheEntry;
+
+/// A map from keys to entries, like a `HashMap`, that can also find an entry by the hash of
+/// its key and its sequence number.
+///
+/// This lets the expiration index refer to entries without holding a copy of every key.
+pub(super) struct EntryTable<K, V> {
+    table: HashTable<(K, CacheEntry<V>)>,
+    hasher: RandomState,
+}
+
+impl<K, V> Default for EntryTable<K, V> {
+    fn default() -> Self {
+        EntryTable {
+            table: HashTable::new(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K, V> EntryTable<K, V> {
+    pub(super) fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&K, &CacheEntry<V>)> {
+        self.table.iter().map(|(k, e)| (k, e))
+    }
+
+    pub(super) fn drain(&mut self) -> impl Iterator<Item = (K, CacheEntry<V>)> + '_ {
+        self.table.drain()
+    }
+
+    pub(super) fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&K, &mut CacheEntry<V>) -> bool,
+    {
+        self.table.retain(|(k, e)| keep(k, e));
+    }
+
+    /// Looks up the entry whose key hashes to `hash`, if it still has the sequence number
+    /// `seq`.
+    ///
+    /// Sequence numbers are never reused, so at most one entry can match.
+    pub(super) fn get_scheduled(&self, hash: u64, seq: u64) -> Option<&CacheEntry<V>> {
+        self.table
+            .find(hash, |(_k, e)| e.seq == seq)
+            .map(|(_k, e)| e)
+    }
+
+    /// Removes the entry whose key hashes to `hash`, if it still has the sequence number
+    /// `seq`.
+    pub(super) fn remove_scheduled(&mut self, hash: u64, seq: u64) -> Option<(K, CacheEntry<V>)> {
+        let found = self.table.find_entry(hash, |(_k, e)| e.seq == seq).ok()?;
+        Some(found.remove().0)
+    }
+}
+
+impl<K, V> EntryTable<K, V>
+where
+    K: Eq + Hash,
+{
+    /// The hash of a key, as used to find its entry.
+    pub(super) fn hash<Q>(&self, key: &Q) -> u64
+    where
+        Q: Hash + ?Sized,
+    {
+        self.hasher.hash_one(key)
+    }
+
+    pub(super) fn get<Q>(&self, key: &Q) -> Option<&CacheEntry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_key_value(key).map(|(_k, e)| e)
+    }
+
+    pub(super) fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &CacheEntry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.table
+            .find(self.hash(key), |(k, _e)| k.borrow() == key)
+            .map(|(k, e)| (k, e))
+    }
+
+    pub(super) fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut CacheEntry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.table
+            .find_mut(self.hasher.hash_one(key), |(k, _e)| k.borrow() == key)
+            .map(|(_k, e)| e)
+    }
+
+    #[cfg(test)]
+    pub(super) fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    pub(super) fn remove_entry<Q>(&mut self, key: &Q) -> Option<(K, CacheEntry<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let found = self
+            .table
+            .find_entry(self.hasher.hash_one(key), |(k, _e)| k.borrow() == key)
+            .ok()?;
+        Some(found.remove().0)
+    }
+
+    /// Inserts an entry under `key`, which hashes to `hash`, and returns the stored key and
+    /// entry, along with the entry that was replaced, if any.
+    ///
+    /// As with `HashMap`, the key that is already stored is kept when an entry is replaced.
+    pub(super) fn insert(
+        &mut self,
+        hash: u64,
+        key: K,
+        entry: CacheEntry<V>,
+    ) -> (&K, &mut CacheEntry<V>, Option<CacheEntry<V>>) {
+        let hasher = &self.hasher;
+        let found = self
+            .table
+            .entry(hash, |(k, _e)| *k == key, |(k, _e)| hasher.hash_one(k));
+        match found {
+            hash_table::Entry::Occupied(occupied) => {
+                let (key, stored) = occupied.into_mut();
+                let replaced = std::mem::replace(stored, entry);
+                (key, stored, Some(replaced))
+            }
+            hash_table::Entry::Vacant(vacant) => {
+                let (key, stored) = vacant.insert((key, entry)).into_mut();
+                (key, stored, None)
+            }
+        }
+    }
+}
+
+impl<K, Q, V> Index<&Q> for EntryTable<K, V>
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+{
+    type Output = CacheEntry<V>;
+
+    fn index(&self, key: &Q) -> &CacheEntry<V> {
+        self.get(key).expect("no entry found for key")
+    }
+}
@@ -0,0 +1,126 @@
+//! Sources of the current time for expiring cache entries.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::time::Instant;
+
+/// Tells a [`TtlCache`](crate::cache::TtlCache) what time it is.
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time, which wall-clock expirations are compared with when they
+    /// are inserted.
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// How a cache allows for wall-clock expirations, such as a token's `exp`, that were set by
+/// another machine whose clock may not agree with this one.
+///
+/// Wall-clock expirations are adjusted by the policy, and then converted to the cache's
+/// [`Clock`] once, when they are inserted, by comparing them with [`Clock::system_now`]. Later
+/// changes to the system clock don't move them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClockSkew {
+    /// Expirations are trusted as given.
+    #[default]
+    Exact,
+    /// Entries expire this much before their expiration, so that values aren't used after
+    /// their issuer may already consider them expired.
+    ExpireEarly(Duration),
+    /// Entries are kept this much past their expiration, so that values aren't dropped while
+    /// an issuer whose clock is behind this one still considers them valid.
+    Leeway(Duration),
+}
+
+impl ClockSkew {
+    /// Applies the policy to a wall-clock expiration.
+    pub fn adjust(self, expires_at: SystemTime) -> SystemTime {
+        match self {
+            ClockSkew::Exact => expires_at,
+            ClockSkew::ExpireEarly(skew) => expires_at.checked_sub(skew).unwrap_or(UNIX_EPOCH),
+            ClockSkew::Leeway(skew) => expires_at.checked_add(skew).unwrap_or(expires_at),
+        }
+    }
+}
+
+/// The clock of caches by default, which reads [`Instant::now`].
+///
+/// This follows tokio's clock, so it also stops while tokio's time is paused.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when it is advanced, for testing expiration deterministically.
+///
+/// Clones share the same time, so one clone can be given to a cache and another kept to
+/// advance it. Its wall-clock time starts at the system time and moves with it. This works
+/// without a tokio runtime.
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use ttl_cache_with_purging::{cache::TtlCache, clock::MockClock};
+///
+/// let clock = MockClock::new();
+/// let mut cache = TtlCache::builder().clock(clock.clone()).build();
+/// cache.insert_with_ttl("key", "val", Duration::from_secs(60));
+///
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(cache.get("key"), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    /// Creates a clock that starts at the current time.
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Moves the clock, and every clone of it, forward.
+    pub fn advance(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + self.elapsed()
+    }
+}
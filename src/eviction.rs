@@ -0,0 +1,693 @@
+//! Policies for choosing which live entries to evict from a bounded cache.
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    hash::{BuildHasher, Hash},
+};
+
+use crate::cache::RemovalCause;
+
+/// Tracks cache usage in order to pick the next live entry to evict once a bounded cache is
+/// full.
+///
+/// Policies are only consulted by bounded caches, and only ever see keys of entries that are
+/// present in the cache.
+pub trait EvictionPolicy<K> {
+    /// Called when a key is inserted into the cache, including when its value is replaced.
+    fn on_insert(&mut self, key: &K);
+
+    /// Called when an unexpired entry is read from the cache.
+    fn on_access(&mut self, key: &K);
+
+    /// Called when a key is removed from the cache for any reason other than eviction by this
+    /// policy, such as expiring or being purged.
+    fn on_remove(&mut self, key: &K, cause: RemovalCause);
+
+    /// Stops tracking the next entry to evict and returns its key, or `None` if there is
+    /// nothing this policy is willing to evict.
+    fn evict(&mut self) -> Option<K>;
+
+    /// Stops tracking every key.
+    fn clear(&mut self);
+}
+
+/// Computes the cost of an entry in a cache bounded by weight.
+///
+/// Weights are computed once per insert, and must not change while the entry is cached.
+pub trait Weigher<K, V> {
+    /// The weight of an entry.
+    fn weigh(&self, key: &K, val: &V) -> u64;
+}
+
+impl<K, V, F> Weigher<K, V> for F
+where
+    F: Fn(&K, &V) -> u64,
+{
+    fn weigh(&self, key: &K, val: &V) -> u64 {
+        self(key, val)
+    }
+}
+
+/// A policy that never evicts live entries.
+///
+/// This is the policy of caches without a capacity limit. A bounded cache using it can only
+/// reclaim expired entries, and will otherwise grow past its limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Unbounded;
+
+impl<K> EvictionPolicy<K> for Unbounded {
+    fn on_insert(&mut self, _key: &K) {}
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, _key: &K, _cause: RemovalCause) {}
+
+    fn evict(&mut self) -> Option<K> {
+        None
+    }
+
+    fn clear(&mut self) {}
+}
+
+/// Evicts the least-recently-used entry, where both inserts and reads count as a use.
+///
+/// Every operation is O(1).
+pub struct Lru<K> {
+    list: KeyedList<K>,
+}
+
+impl<K> Lru<K> {
+    /// Creates a new LRU policy.
+    pub fn new() -> Self {
+        Lru {
+            list: KeyedList::new(),
+        }
+    }
+}
+
+impl<K> Default for Lru<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> EvictionPolicy<K> for Lru<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&mut self, key: &K) {
+        if !self.list.move_to_front(key) {
+            self.list.push_front(key.clone());
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.list.move_to_front(key);
+    }
+
+    fn on_remove(&mut self, key: &K, _cause: RemovalCause) {
+        self.list.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.list.pop_back()
+    }
+
+    fn clear(&mut self) {
+        self.list = KeyedList::new();
+    }
+}
+
+/// Evicts the oldest entry, ignoring reads. Replacing a value does not change its age.
+///
+/// Every operation is O(1).
+pub struct Fifo<K> {
+    list: KeyedList<K>,
+}
+
+impl<K> Fifo<K> {
+    /// Creates a new FIFO policy.
+    pub fn new() -> Self {
+        Fifo {
+            list: KeyedList::new(),
+        }
+    }
+}
+
+impl<K> Default for Fifo<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> EvictionPolicy<K> for Fifo<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&mut self, key: &K) {
+        if !self.list.contains(key) {
+            self.list.push_front(key.clone());
+        }
+    }
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, key: &K, _cause: RemovalCause) {
+        self.list.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.list.pop_back()
+    }
+
+    fn clear(&mut self) {
+        self.list = KeyedList::new();
+    }
+}
+
+/// Evicts the least-frequently-used entry, breaking ties by evicting the least recently used.
+///
+/// Both inserts and reads count as a use. Operations are O(log n) in the number of distinct
+/// use counts.
+pub struct Lfu<K> {
+    buckets: BTreeMap<u64, LinkedSlab<K>>,
+    index: HashMap<K, (u64, usize)>,
+}
+
+impl<K> Lfu<K> {
+    /// Creates a new LFU policy.
+    pub fn new() -> Self {
+        Lfu {
+            buckets: BTreeMap::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<K> Default for Lfu<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> Lfu<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn take(&mut self, uses: u64, node: usize) -> K {
+        let bucket = self
+            .buckets
+            .get_mut(&uses)
+            .expect("indexed keys have a bucket");
+        let key = bucket.remove(node);
+        if bucket.is_empty() {
+            self.buckets.remove(&uses);
+        }
+        key
+    }
+
+    fn place(&mut self, key: K, uses: u64) {
+        let node = self
+            .buckets
+            .entry(uses)
+            .or_default()
+            .push_front(key.clone());
+        self.index.insert(key, (uses, node));
+    }
+}
+
+impl<K> EvictionPolicy<K> for Lfu<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&mut self, key: &K) {
+        match self.index.get(key) {
+            Some(_) => self.on_access(key),
+            None => self.place(key.clone(), 1),
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if let Some(&(uses, node)) = self.index.get(key) {
+            let key = self.take(uses, node);
+            self.place(key, uses.saturating_add(1));
+        }
+    }
+
+    fn on_remove(&mut self, key: &K, _cause: RemovalCause) {
+        if let Some((uses, node)) = self.index.remove(key) {
+            self.take(uses, node);
+        }
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        let mut bucket = self.buckets.first_entry()?;
+        let key = bucket.get_mut().pop_back()?;
+        if bucket.get().is_empty() {
+            bucket.remove();
+        }
+        self.index.remove(&key);
+        Some(key)
+    }
+
+    fn clear(&mut self) {
+        self.buckets.clear();
+        self.index.clear();
+    }
+}
+
+/// Window TinyLFU, which keeps scans and one-off keys from flushing frequently used entries.
+///
+/// New entries land in a small LRU window. Entries that overflow the window are only kept
+/// once the cache is full if they have been used more often than the entry they would
+/// replace in the main cache, as estimated by a compact frequency sketch. The main cache is a
+/// segmented LRU, where entries that are read again are protected from eviction.
+///
+/// Every operation is O(1).
+pub struct TinyLfu<K> {
+    window: KeyedList<K>,
+    probation: KeyedList<K>,
+    protected: KeyedList<K>,
+    window_capacity: usize,
+    protected_capacity: usize,
+    sketch: FrequencySketch,
+}
+
+impl<K> TinyLfu<K> {
+    /// Creates a new W-TinyLFU policy, sized for a cache bounded to `capacity_limit` entries.
+    pub fn new(capacity_limit: usize) -> Self {
+        let window_capacity = (capacity_limit / 100).max(1);
+        let main_capacity = capacity_limit.saturating_sub(window_capacity);
+        TinyLfu {
+            window: KeyedList::new(),
+            probation: KeyedList::new(),
+            protected: KeyedList::new(),
+            window_capacity,
+            protected_capacity: main_capacity * 4 / 5,
+            sketch: FrequencySketch::new(capacity_limit),
+        }
+    }
+}
+
+impl<K> EvictionPolicy<K> for TinyLfu<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn on_insert(&mut self, key: &K) {
+        if self.window.contains(key) || self.probation.contains(key) || self.protected.contains(key)
+        {
+            self.on_access(key);
+        } else {
+            self.sketch.increment(key);
+            self.window.push_front(key.clone());
+            if self.window.len() > self.window_capacity {
+                if let Some(overflow) = self.window.pop_back() {
+                    self.probation.push_front(overflow);
+                }
+            }
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.sketch.increment(key);
+        if self.window.move_to_front(key) || self.protected.move_to_front(key) {
+            return;
+        }
+        if let Some(key) = self.probation.remove(key) {
+            self.protected.push_front(key);
+            if self.protected.len() > self.protected_capacity {
+                if let Some(demoted) = self.protected.pop_back() {
+                    self.probation.push_front(demoted);
+                }
+            }
+        }
+    }
+
+    fn on_remove(&mut self, key: &K, _cause: RemovalCause) {
+        if self.window.remove(key).is_none() && self.probation.remove(key).is_none() {
+            self.protected.remove(key);
+        }
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        // The newest entry in probation overflowed the window most recently, so it is the
+        // candidate for admission against the entry that probation would evict.
+        if self.probation.len() > 1 {
+            let candidate = self.probation.front().expect("probation is not empty");
+            let victim = self.probation.back().expect("probation is not empty");
+            if self.sketch.frequency(candidate) <= self.sketch.frequency(victim) {
+                return self.probation.pop_front();
+            }
+        }
+        self.probation
+            .pop_back()
+            .or_else(|| self.protected.pop_back())
+            .or_else(|| self.window.pop_back())
+    }
+
+    fn clear(&mut self) {
+        self.window = KeyedList::new();
+        self.probation = KeyedList::new();
+        self.protected = KeyedList::new();
+    }
+}
+
+/// A count-min sketch of 4-bit counters that estimates how often keys have been used.
+///
+/// All counters are halved periodically, so that keys that were popular long ago age out.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    reset_at: usize,
+    hasher: RandomState,
+}
+
+const SKETCH_DEPTH: usize = 4;
+const MAX_FREQUENCY: u8 = 15;
+
+impl FrequencySketch {
+    fn new(capacity_limit: usize) -> Self {
+        let capacity_limit = capacity_limit.max(16);
+        let width = (capacity_limit * 4).next_power_of_two();
+        FrequencySketch {
+            counters: vec![0; width * SKETCH_DEPTH],
+            mask: width - 1,
+            additions: 0,
+            reset_at: capacity_limit * 10,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn slots<K: Hash>(&self, key: &K) -> [usize; SKETCH_DEPTH] {
+        let hash = self.hasher.hash_one(key);
+        let width = self.mask + 1;
+        std::array::from_fn(|row| {
+            let h = hash.rotate_left(16 * row as u32) as usize;
+            row * width + (h & self.mask)
+        })
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for slot in self.slots(key) {
+            if self.counters[slot] < MAX_FREQUENCY {
+                self.counters[slot] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.reset_at {
+            self.counters.iter_mut().for_each(|c| *c /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.slots(key)
+            .into_iter()
+            .map(|slot| self.counters[slot])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// A [`LinkedSlab`] of keys that can also be addressed by key in O(1).
+struct KeyedList<K> {
+    list: LinkedSlab<K>,
+    index: HashMap<K, usize>,
+}
+
+impl<K> KeyedList<K> {
+    fn new() -> Self {
+        KeyedList {
+            list: LinkedSlab::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn front(&self) -> Option<&K> {
+        self.list.front()
+    }
+
+    fn back(&self) -> Option<&K> {
+        self.list.back()
+    }
+}
+
+impl<K> KeyedList<K>
+where
+    K: Clone + Eq + Hash,
+{
+    fn contains(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    fn push_front(&mut self, key: K) {
+        let node = self.list.push_front(key.clone());
+        self.index.insert(key, node);
+    }
+
+    /// Moves a key to the front of the list, returning `false` if it is not present.
+    fn move_to_front(&mut self, key: &K) -> bool {
+        match self.index.get(key) {
+            Some(&node) => {
+                self.list.move_to_front(node);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<K> {
+        let node = self.index.remove(key)?;
+        Some(self.list.remove(node))
+    }
+
+    fn pop_front(&mut self) -> Option<K> {
+        let key = self.list.pop_front()?;
+        self.index.remove(&key);
+        Some(key)
+    }
+
+    fn pop_back(&mut self) -> Option<K> {
+        let key = self.list.pop_back()?;
+        self.index.remove(&key);
+        Some(key)
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+/// A doubly-linked list whose nodes live in a `Vec`, so they can be addressed by index in O(1).
+struct LinkedSlab<T> {
+    nodes: Vec<Node<T>>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+}
+
+struct Node<T> {
+    val: Option<T>,
+    prev: usize,
+    next: usize,
+}
+
+impl<T> Default for LinkedSlab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LinkedSlab<T> {
+    fn new() -> Self {
+        LinkedSlab {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head == NIL
+    }
+
+    fn front(&self) -> Option<&T> {
+        self.nodes.get(self.head).and_then(|node| node.val.as_ref())
+    }
+
+    fn back(&self) -> Option<&T> {
+        self.nodes.get(self.tail).and_then(|node| node.val.as_ref())
+    }
+
+    fn push_front(&mut self, val: T) -> usize {
+        let node = Node {
+            val: Some(val),
+            prev: NIL,
+            next: NIL,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.link_front(idx);
+        idx
+    }
+
+    fn move_to_front(&mut self, idx: usize) {
+        if self.head != idx {
+            self.unlink(idx);
+            self.link_front(idx);
+        }
+    }
+
+    fn remove(&mut self, idx: usize) -> T {
+        self.unlink(idx);
+        self.free.push(idx);
+        self.nodes[idx]
+            .val
+            .take()
+            .expect("linked nodes hold a value")
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        match self.head {
+            NIL => None,
+            head => Some(self.remove(head)),
+        }
+    }
+
+    fn pop_back(&mut self) -> Option<T> {
+        match self.tail {
+            NIL => None,
+            tail => Some(self.remove(tail)),
+        }
+    }
+
+    fn link_front(&mut self, idx: usize) {
+        self.nodes[idx].prev = NIL;
+        self.nodes[idx].next = self.head;
+        match self.head {
+            NIL => self.tail = idx,
+            head => self.nodes[head].prev = idx,
+        }
+        self.head = idx;
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let Node { prev, next, .. } = self.nodes[idx];
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::RemovalCause;
+    use crate::eviction::{EvictionPolicy, Fifo, Lfu, Lru, TinyLfu};
+
+    fn drain<K>(policy: &mut impl EvictionPolicy<K>) -> Vec<K> {
+        std::iter::from_fn(|| policy.evict()).collect()
+    }
+
+    #[test]
+    fn given_tracked_keys_when_evicting_then_the_least_recently_used_key_is_evicted_first() {
+        // Arrange
+        let mut lru = Lru::new();
+        lru.on_insert(&"a");
+        lru.on_insert(&"b");
+        lru.on_insert(&"c");
+        lru.on_access(&"a");
+        lru.on_remove(&"b", RemovalCause::Expired);
+
+        // Act
+        let evicted = drain(&mut lru);
+
+        // Assert
+        assert_eq!(evicted, vec!["c", "a"]);
+    }
+
+    #[test]
+    fn given_tracked_keys_when_evicting_with_fifo_then_reads_and_replacements_are_ignored() {
+        // Arrange
+        let mut fifo = Fifo::new();
+        fifo.on_insert(&"a");
+        fifo.on_insert(&"b");
+        fifo.on_access(&"a");
+        fifo.on_insert(&"a");
+
+        // Act
+        let evicted = drain(&mut fifo);
+
+        // Assert
+        assert_eq!(evicted, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn given_tracked_keys_when_evicting_with_lfu_then_the_least_frequently_used_key_is_evicted_first(
+    ) {
+        // Arrange
+        let mut lfu = Lfu::new();
+        lfu.on_insert(&"a");
+        lfu.on_insert(&"b");
+        lfu.on_insert(&"c");
+        lfu.on_access(&"a");
+        lfu.on_access(&"a");
+        lfu.on_access(&"c");
+
+        // Act
+        let evicted = drain(&mut lfu);
+
+        // Assert
+        assert_eq!(evicted, vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn given_frequently_used_keys_when_a_scan_runs_then_tiny_lfu_evicts_the_scanned_keys_instead() {
+        // Arrange
+        let capacity_limit = 100;
+        let hot_keys = 50;
+        let mut tiny_lfu = TinyLfu::new(capacity_limit);
+        let mut tracked = 0;
+        for hot in 0..hot_keys {
+            tiny_lfu.on_insert(&hot);
+            tracked += 1;
+            for _ in 0..3 {
+                tiny_lfu.on_access(&hot);
+            }
+        }
+
+        // Act
+        for (i, scanned) in (1000..2000).enumerate() {
+            if tracked == capacity_limit {
+                tiny_lfu.evict().unwrap();
+                tracked -= 1;
+            }
+            tiny_lfu.on_insert(&scanned);
+            tracked += 1;
+            if i % 4 == 0 {
+                tiny_lfu.on_access(&((i / 4) % hot_keys));
+            }
+        }
+
+        // Assert
+        // The sketch is approximate and randomly seeded, so a collision may occasionally let a
+        // scanned key displace a hot one. An LRU policy would keep none of them.
+        let survivors = drain(&mut tiny_lfu);
+        assert!(survivors.iter().filter(|&&key| key < hot_keys).count() >= 45);
+    }
+}
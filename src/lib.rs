@@ -18,4 +18,13 @@
 #![doc = include_str!("../examples/example.rs")]
 //! ```
 pub mod cache;
+pub mod clock;
+pub mod eviction;
+pub mod loading;
+#[cfg(feature = "persistence")]
+pub mod persistence;
 pub mod purging;
+pub mod sharded;
+pub mod shared;
+#[cfg(feature = "wal")]
+pub mod wal;
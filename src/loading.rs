@@ -0,0 +1,587 @@
+//! A cache that loads missing entries itself, and refreshes entries before they expire.
+use std::{
+    collections::HashSet,
+    future::Future,
+    hash::Hash,
+    ops::ControlFlow,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, Weak},
+    time::Duration,
+};
+
+use tokio::time::{Instant, Interval};
+
+use crate::{
+    cache::{Expiration, Purgeable, TtlCache},
+    clock::{Clock, RealClock},
+    eviction::{EvictionPolicy, Unbounded},
+    purging::{spawn_periodic, PurgeHandle},
+    shared::SharedTtlCache,
+};
+
+/// Loads values into a [`LoadingTtlCache`], typically from an upstream service.
+///
+/// Closures that take a key and return a future are loaders.
+pub trait Loader<K, V> {
+    /// The error returned when a value can't be loaded.
+    type Error;
+
+    /// Loads the value for a key, along with when it expires.
+    fn load(&self, key: &K) -> impl Future<Output = Result<(V, Expiration), Self::Error>> + Send;
+}
+
+impl<K, V, F, Fut, E> Loader<K, V> for F
+where
+    F: Fn(&K) -> Fut,
+    Fut: Future<Output = Result<(V, Expiration), E>> + Send,
+{
+    type Error = E;
+
+    fn load(&self, key: &K) -> impl Future<Output = Result<(V, Expiration), E>> + Send {
+        self(key)
+    }
+}
+
+/// When an entry is due to be refreshed ahead of its expiration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefreshAhead {
+    /// The entry is refreshed once it expires within the given window.
+    Window(Duration),
+    /// The entry is refreshed once the given fraction of its lifetime has passed, between
+    /// `0.0` and `1.0`.
+    Fraction(f64),
+}
+
+impl RefreshAhead {
+    fn is_due(self, inserted_at: Instant, expires_at: Instant, now: Instant) -> bool {
+        let refresh_at = match self {
+            RefreshAhead::Window(window) => expires_at.checked_sub(window).unwrap_or(inserted_at),
+            RefreshAhead::Fraction(fraction) => {
+                let lifetime = expires_at.saturating_duration_since(inserted_at);
+                inserted_at + lifetime.mul_f64(fraction.clamp(0.0, 1.0))
+            }
+        };
+        now >= refresh_at
+    }
+}
+
+/// A cloneable, thread-safe cache that loads missing entries through a [`Loader`].
+///
+/// Loads are coalesced as described in [`SharedTtlCache::get_or_try_insert_with`]. With
+/// refresh-ahead configured, reads of an entry that is close to expiring start reloading it in
+/// the background and return the current value, so that hot entries are never reloaded inline.
+///
+/// If the underlying cache was built with a
+/// [grace period](crate::cache::TtlCacheBuilder::grace_period), expired values can also be
+/// served while they are reloaded, or when reloading them fails. See
+/// [`LoadingTtlCacheBuilder::stale_while_revalidate`] and
+/// [`LoadingTtlCacheBuilder::stale_if_error`].
+///
+/// ```rust
+/// use std::{convert::Infallible, time::Duration};
+///
+/// use ttl_cache_with_purging::{
+///     cache::{Expiration, TtlCache},
+///     loading::{LoadingTtlCache, RefreshAhead},
+/// };
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let cache = LoadingTtlCache::builder(TtlCache::new(), |key: &&str| {
+///     let val = key.to_uppercase();
+///     async move { Ok::<_, Infallible>((val, Expiration::After(Duration::from_secs(60)))) }
+/// })
+/// .refresh_ahead(RefreshAhead::Fraction(0.8))
+/// .build();
+///
+/// assert_eq!(cache.get("key").await, Ok("KEY".to_string()));
+/// # }
+/// ```
+pub struct LoadingTtlCache<K, V, L, P = Unbounded, C = RealClock> {
+    inner: Arc<Inner<K, V, L, P, C>>,
+}
+
+struct Inner<K, V, L, P, C> {
+    cache: SharedTtlCache<K, V, P, C>,
+    loader: L,
+    refresh_ahead: Option<RefreshAhead>,
+    stale_while_revalidate: bool,
+    stale_if_error: bool,
+    /// The keys being refreshed in the background, so each is only refreshed once at a time.
+    refreshing: Mutex<HashSet<K>>,
+    purge_handle: Mutex<Option<PurgeHandle>>,
+}
+
+/// Forgets a background refresh once it is done, even if the loader panicked.
+struct RefreshGuard<K, V, L, P, C>
+where
+    K: Eq + Hash,
+{
+    inner: Arc<Inner<K, V, L, P, C>>,
+    key: K,
+}
+
+/// Builds a [`LoadingTtlCache`] with non-default settings.
+pub struct LoadingTtlCacheBuilder<K, V, L, P = Unbounded, C = RealClock> {
+    cache: SharedTtlCache<K, V, P, C>,
+    loader: L,
+    refresh_ahead: Option<RefreshAhead>,
+    stale_while_revalidate: bool,
+    stale_if_error: bool,
+}
+
+impl<K, V, L, P, C> Clone for LoadingTtlCache<K, V, L, P, C> {
+    fn clone(&self) -> Self {
+        LoadingTtlCache {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V, L, P, C> LoadingTtlCache<K, V, L, P, C>
+where
+    K: Clone + Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Creates a cache that loads missing entries through `loader`.
+    pub fn new(cache: impl Into<SharedTtlCache<K, V, P, C>>, loader: L) -> Self {
+        Self::builder(cache, loader).build()
+    }
+
+    /// Creates a builder for a cache that loads missing entries through `loader`.
+    pub fn builder(
+        cache: impl Into<SharedTtlCache<K, V, P, C>>,
+        loader: L,
+    ) -> LoadingTtlCacheBuilder<K, V, L, P, C> {
+        LoadingTtlCacheBuilder {
+            cache: cache.into(),
+            loader,
+            refresh_ahead: None,
+            stale_while_revalidate: false,
+            stale_if_error: false,
+        }
+    }
+
+    /// The underlying cache, which can be used to read or insert entries without loading them.
+    pub fn cache(&self) -> &SharedTtlCache<K, V, P, C> {
+        &self.inner.cache
+    }
+}
+
+impl<K, V, L, P, C> LoadingTtlCache<K, V, L, P, C>
+where
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    L: Loader<K, V> + Send + Sync + 'static,
+    P: EvictionPolicy<K> + Send + Sync + 'static,
+    C: Clock + Send + Sync + 'static,
+{
+    /// Retrieves a clone of an unexpired value from the cache, loading it if it is missing or
+    /// expired.
+    ///
+    /// Entries that are due for refresh-ahead are returned as they are, and reloaded in the
+    /// background. Expired entries that are still in the cache's grace period may be returned
+    /// as well, as configured by [`LoadingTtlCacheBuilder::stale_while_revalidate`] and
+    /// [`LoadingTtlCacheBuilder::stale_if_error`].
+    pub async fn get(&self, key: K) -> Result<V, L::Error> {
+        let refresh_ahead = self.inner.refresh_ahead;
+        let cached = self
+            .inner
+            .cache
+            .read(|cache| {
+                cache
+                    .get_value_and_lifetime(&key)
+                    .map(|(val, inserted_at, expires_at)| {
+                        let due = refresh_ahead.is_some_and(|refresh_ahead| {
+                            refresh_ahead.is_due(inserted_at, expires_at, cache.clock().now())
+                        });
+                        (val.clone(), due)
+                    })
+            })
+            .await;
+
+        match cached {
+            Some((val, refresh_due)) => {
+                if refresh_due {
+                    self.refresh(key);
+                }
+                Ok(val)
+            }
+            None => {
+                let stale = if self.inner.stale_while_revalidate || self.inner.stale_if_error {
+                    self.inner.cache.get_stale(&key).await.map(|(val, _)| val)
+                } else {
+                    None
+                };
+                if self.inner.stale_while_revalidate {
+                    if let Some(val) = stale {
+                        self.refresh(key);
+                        return Ok(val);
+                    }
+                }
+
+                let loader = &self.inner.loader;
+                let load_key = key.clone();
+                let loaded = self
+                    .inner
+                    .cache
+                    .get_or_try_insert_with(key, || load(loader, &load_key, false))
+                    .await;
+                match (loaded, stale) {
+                    (Err(_), Some(val)) if self.inner.stale_if_error => Ok(val),
+                    (loaded, _) => loaded,
+                }
+            }
+        }
+    }
+
+    /// Starts reloading a key in the background, unless it is already being reloaded.
+    ///
+    /// The current value, if any, stays cached until the reload succeeds. Failed reloads are
+    /// ignored, and the current value expires as it would have otherwise.
+    pub fn refresh(&self, key: K) {
+        if !self.inner.refreshing().insert(key.clone()) {
+            return;
+        }
+        let refresh = RefreshGuard {
+            inner: self.inner.clone(),
+            key,
+        };
+        tokio::spawn(async move {
+            let RefreshGuard { inner, key } = &refresh;
+            let loaded = load(&inner.loader, key, true).await.ok();
+            if let Some((val, expiration)) = loaded {
+                inner
+                    .cache
+                    .insert_with_expiration(key.clone(), val, expiration)
+                    .await;
+            }
+        });
+    }
+
+    /// Starts purging expired entries at the specified interval, replacing any purge task
+    /// that was already running.
+    ///
+    /// With refresh-ahead configured, each purge also starts refreshing every entry that is
+    /// due, so that entries that aren't read are refreshed too. Finding them scans every entry
+    /// while holding the cache's read lock.
+    ///
+    /// The task stops once every clone of the cache is dropped.
+    pub fn start_purging(&self, purge_interval: Interval) {
+        let inner = Arc::downgrade(&self.inner);
+        let purge_handle = spawn_periodic(purge_interval, move || {
+            let inner = inner.clone();
+            async move {
+                let Some(inner) = Weak::upgrade(&inner) else {
+                    return ControlFlow::Break(());
+                };
+                LoadingTtlCache { inner }.purge_and_refresh().await;
+                ControlFlow::Continue(())
+            }
+        });
+        *self.inner.purge_handle() = Some(purge_handle);
+    }
+
+    /// Stops purging expired entries, if a purge task was running.
+    pub fn stop_purging(&self) {
+        self.inner.purge_handle().take();
+    }
+
+    async fn purge_and_refresh(&self) {
+        if let Some(refresh_ahead) = self.inner.refresh_ahead {
+            let due = self
+                .inner
+                .cache
+                .read(|cache| {
+                    let now = cache.clock().now();
+                    cache.live_keys_where(|inserted_at, expires_at| {
+                        refresh_ahead.is_due(inserted_at, expires_at, now)
+                    })
+                })
+                .await;
+            for key in due {
+                self.refresh(key);
+            }
+        }
+        self.inner.cache.write(TtlCache::purge_expired).await;
+    }
+}
+
+impl<K, V, L, P, C> LoadingTtlCacheBuilder<K, V, L, P, C>
+where
+    K: Clone + Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Reloads entries in the background once they are due, as described by
+    /// [`RefreshAhead`].
+    pub fn refresh_ahead(mut self, refresh_ahead: RefreshAhead) -> Self {
+        self.refresh_ahead = Some(refresh_ahead);
+        self
+    }
+
+    /// Returns expired values that are still in the cache's grace period right away, and
+    /// reloads them in the background, instead of waiting for the reload.
+    pub fn stale_while_revalidate(mut self) -> Self {
+        self.stale_while_revalidate = true;
+        self
+    }
+
+    /// Returns expired values that are still in the cache's grace period when reloading them
+    /// fails, instead of the loader's error.
+    pub fn stale_if_error(mut self) -> Self {
+        self.stale_if_error = true;
+        self
+    }
+
+    /// Creates the configured cache.
+    pub fn build(self) -> LoadingTtlCache<K, V, L, P, C> {
+        LoadingTtlCache {
+            inner: Arc::new(Inner {
+                cache: self.cache,
+                loader: self.loader,
+                refresh_ahead: self.refresh_ahead,
+                stale_while_revalidate: self.stale_while_revalidate,
+                stale_if_error: self.stale_if_error,
+                refreshing: Mutex::new(HashSet::new()),
+                purge_handle: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+impl<K, V, L, P, C> Inner<K, V, L, P, C> {
+    fn refreshing(&self) -> MutexGuard<'_, HashSet<K>> {
+        self.refreshing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn purge_handle(&self) -> MutexGuard<'_, Option<PurgeHandle>> {
+        self.purge_handle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K, V, L, P, C> Drop for RefreshGuard<K, V, L, P, C>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        self.inner.refreshing().remove(&self.key);
+    }
+}
+
+/// Loads the value for a key through the loader.
+///
+/// With the `tracing` feature, each load runs in a `load` span that records whether it is a
+/// background `refresh`, whether it succeeded, and how long it took, in microseconds.
+async fn load<K, V, L>(loader: &L, key: &K, refresh: bool) -> Result<(V, Expiration), L::Error>
+where
+    L: Loader<K, V>,
+{
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::{field::Empty, Instrument};
+
+        let span = tracing::debug_span!("load", refresh, success = Empty, elapsed_us = Empty);
+        let started = std::time::Instant::now();
+        let loaded = loader.load(key).instrument(span.clone()).await;
+        span.record("success", loaded.is_ok()).record(
+            "elapsed_us",
+            u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX),
+        );
+        loaded
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = refresh;
+        loader.load(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    use tokio::time::{advance, interval, sleep};
+
+    #[cfg(feature = "tracing")]
+    use crate::cache::test_helpers::SpanRecorder;
+    use crate::cache::{Expiration, TtlCache};
+    use crate::loading::{LoadingTtlCache, RefreshAhead};
+
+    /// A loader that returns how many times it has been called.
+    fn counting_loader(
+        loads: Arc<AtomicUsize>,
+    ) -> impl Fn(&&'static str) -> std::future::Ready<Result<(usize, Expiration), ()>> {
+        move |_key| {
+            let load = loads.fetch_add(1, Ordering::SeqCst) + 1;
+            std::future::ready(Ok((load, Expiration::After(Duration::from_secs(10)))))
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn given_tracing_is_enabled_when_a_value_is_loaded_then_the_loader_runs_in_a_load_span() {
+        // Arrange
+        let recorder = SpanRecorder::default();
+        let _subscriber = tracing::subscriber::set_default(recorder.clone());
+        let loader = {
+            let recorder = recorder.clone();
+            move |_key: &&str| {
+                let recorder = recorder.clone();
+                async move {
+                    let span = recorder.current_name();
+                    Ok::<_, ()>((span, Expiration::After(Duration::from_secs(10))))
+                }
+            }
+        };
+        let cache = LoadingTtlCache::new(TtlCache::new(), loader);
+
+        // Act
+        let loaded = cache.get("key").await;
+
+        // Assert
+        assert_eq!(loaded, Ok(Some("load")));
+        let spans = recorder.spans("load");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].get("refresh").map(String::as_str), Some("false"));
+        assert_eq!(spans[0].get("success").map(String::as_str), Some("true"));
+        assert!(spans[0].contains_key("elapsed_us"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_an_entry_due_for_refresh_when_reading_it_then_the_current_value_is_returned_and_it_is_reloaded(
+    ) {
+        // Arrange
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = LoadingTtlCache::builder(TtlCache::new(), counting_loader(loads.clone()))
+            .refresh_ahead(RefreshAhead::Window(Duration::from_secs(2)))
+            .build();
+        let first = cache.get("key").await;
+
+        // Act
+        advance(Duration::from_secs(9)).await;
+        let during_refresh = cache.get("key").await;
+        sleep(Duration::from_millis(1)).await;
+        let after_refresh = cache.get("key").await;
+
+        // Assert
+        assert_eq!(first, Ok(1));
+        assert_eq!(during_refresh, Ok(1));
+        assert_eq!(after_refresh, Ok(2));
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_refresh_whose_loader_panics_when_the_entry_is_read_again_then_it_is_refreshed_again(
+    ) {
+        // Arrange
+        let loads = Arc::new(AtomicUsize::new(0));
+        let loader = {
+            let loads = loads.clone();
+            move |_key: &&str| {
+                let load = loads.fetch_add(1, Ordering::SeqCst) + 1;
+                assert_ne!(load, 2, "the first refresh panics");
+                std::future::ready(Ok::<_, ()>((
+                    load,
+                    Expiration::After(Duration::from_secs(10)),
+                )))
+            }
+        };
+        let cache = LoadingTtlCache::builder(TtlCache::new(), loader)
+            .refresh_ahead(RefreshAhead::Window(Duration::from_secs(2)))
+            .build();
+        cache.get("key").await.unwrap();
+        advance(Duration::from_secs(9)).await;
+        cache.get("key").await.unwrap();
+        sleep(Duration::from_millis(1)).await;
+
+        // Act
+        let after_panic = cache.get("key").await;
+        sleep(Duration::from_millis(1)).await;
+        let after_refresh = cache.get("key").await;
+
+        // Assert
+        assert_eq!(after_panic, Ok(1));
+        assert_eq!(after_refresh, Ok(3));
+        assert_eq!(loads.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_an_expired_entry_in_its_grace_period_when_the_loader_fails_then_the_stale_value_is_returned(
+    ) {
+        // Arrange
+        let ttl_cache = TtlCache::builder()
+            .grace_period(Duration::from_secs(60))
+            .build();
+        let cache = LoadingTtlCache::builder(ttl_cache, |_key: &&str| {
+            std::future::ready(Err::<(&str, Expiration), _>("unavailable"))
+        })
+        .stale_if_error()
+        .build();
+        cache
+            .cache()
+            .insert_with_ttl("key", "val", Duration::from_secs(10))
+            .await;
+
+        // Act
+        advance(Duration::from_secs(30)).await;
+        let stale = cache.get("key").await;
+        advance(Duration::from_secs(60)).await;
+        let past_grace_period = cache.get("key").await;
+
+        // Assert
+        assert_eq!(stale, Ok("val"));
+        assert_eq!(past_grace_period, Err("unavailable"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_an_expired_entry_in_its_grace_period_when_reading_it_while_revalidating_then_the_stale_value_is_returned_and_it_is_reloaded(
+    ) {
+        // Arrange
+        let loads = Arc::new(AtomicUsize::new(0));
+        let ttl_cache = TtlCache::builder()
+            .grace_period(Duration::from_secs(60))
+            .build();
+        let cache = LoadingTtlCache::builder(ttl_cache, counting_loader(loads.clone()))
+            .stale_while_revalidate()
+            .build();
+        cache.get("key").await.unwrap();
+
+        // Act
+        advance(Duration::from_secs(20)).await;
+        let stale = cache.get("key").await;
+        sleep(Duration::from_millis(1)).await;
+        let revalidated = cache.get("key").await;
+
+        // Assert
+        assert_eq!(stale, Ok(1));
+        assert_eq!(revalidated, Ok(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_an_unread_entry_due_for_refresh_when_the_cache_purges_then_it_is_reloaded() {
+        // Arrange
+        let loads = Arc::new(AtomicUsize::new(0));
+        let cache = LoadingTtlCache::builder(TtlCache::new(), counting_loader(loads.clone()))
+            .refresh_ahead(RefreshAhead::Fraction(0.5))
+            .build();
+        cache.get("key").await.unwrap();
+
+        // Act
+        cache.start_purging(interval(Duration::from_secs(6)));
+        advance(Duration::from_secs(6)).await;
+        sleep(Duration::from_millis(1)).await;
+
+        // Assert
+        assert_eq!(loads.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.cache().get("key").await, Some(2));
+    }
+}
@@ -0,0 +1,293 @@
+//! Persisting cache contents to a local file, for warm restarts.
+//!
+//! Snapshot files start with a header line naming the format and its version, followed by the
+//! [`Snapshot`] as JSON. Files are replaced atomically, by writing a uniquely named temporary
+//! file next to them and renaming it over the old one, so a crash mid-write leaves the previous
+//! snapshot intact, and concurrent writes don't corrupt each other. On Unix, the directory is
+//! synced after the rename so that the new snapshot survives a power loss too, and snapshot
+//! files are only readable by their owner.
+use std::{
+    fs::File,
+    hash::Hash,
+    io::{self, BufRead, BufReader, BufWriter, IntoInnerError, Write},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{sync::RwLock, time::Interval};
+
+use crate::{
+    cache::{Snapshot, TtlCache},
+    clock::Clock,
+    eviction::EvictionPolicy,
+    purging::{spawn_periodic, TaskHandle},
+};
+
+/// The start of every snapshot file's header line, which is followed by the format version.
+const HEADER: &str = "ttl_cache_with_purging snapshot";
+
+/// The version of the snapshot format written by this crate.
+const VERSION: u32 = 1;
+
+/// Kick-off a background task that will write the unexpired contents of the cache to `path`
+/// at the specified interval.
+///
+/// Snapshots are taken under the cache's read lock, and written without holding it. Errors
+/// don't stop the task, which tries again on its next tick; with the `tracing` feature, they
+/// are logged as warnings. Stopping the task doesn't write a final snapshot, so call
+/// [`write_snapshot`] on shutdown to keep the most recent entries.
+///
+/// The task runs until the returned handle is stopped or dropped.
+pub fn start_periodic_persist<K, V, P, C>(
+    cache: Arc<RwLock<TtlCache<K, V, P, C>>>,
+    path: impl Into<PathBuf>,
+    persist_interval: Interval,
+) -> TaskHandle
+where
+    K: Serialize + Clone + Eq + Hash + Send + Sync + 'static,
+    V: Serialize + Clone + Send + Sync + 'static,
+    P: EvictionPolicy<K> + Send + Sync + 'static,
+    C: Clock + Send + Sync + 'static,
+{
+    let path = path.into();
+    spawn_periodic(persist_interval, move || {
+        let cache = cache.clone();
+        let path = path.clone();
+        async move {
+            let snapshot = cache.read().await.snapshot();
+            let snapshot_path = path.clone();
+            let written =
+                tokio::task::spawn_blocking(move || write_snapshot(&snapshot_path, &snapshot))
+                    .await
+                    .unwrap_or_else(|error| Err(io::Error::other(error)));
+            #[cfg(feature = "tracing")]
+            if let Err(error) = &written {
+                tracing::warn!(path = %path.display(), %error, "failed to persist cache");
+            }
+            #[cfg(not(feature = "tracing"))]
+            let _ = written;
+            ControlFlow::Continue(())
+        }
+    })
+}
+
+/// Inserts the entries of the snapshot file at `path` into the cache, as with
+/// [`TtlCache::restore`], and returns how many were inserted.
+///
+/// A missing file restores nothing, as on the first start.
+pub fn restore_from_file<K, V, P, C>(
+    cache: &mut TtlCache<K, V, P, C>,
+    path: impl AsRef<Path>,
+) -> io::Result<usize>
+where
+    K: DeserializeOwned + Eq + Hash,
+    V: DeserializeOwned,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    Ok(read_snapshot(path)?.map_or(0, |snapshot| cache.restore(snapshot)))
+}
+
+/// Atomically replaces the file at `path` with the snapshot.
+pub fn write_snapshot<K, V>(path: impl AsRef<Path>, snapshot: &Snapshot<K, V>) -> io::Result<()>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    let path = path.as_ref();
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "snapshot path has no file name",
+        )
+    })?;
+    let mut prefix = file_name.to_owned();
+    prefix.push(".");
+    // Removed when dropped, unless it has been renamed into place.
+    let temp_file = tempfile::Builder::new()
+        .prefix(&prefix)
+        .suffix(".tmp")
+        .tempfile_in(parent_dir(path))?;
+    write_file(temp_file.as_file(), snapshot)?;
+    temp_file.persist(path).map_err(|error| error.error)?;
+    sync_parent_dir(path)
+}
+
+/// Reads the snapshot file at `path`, or returns `None` if there is no such file.
+///
+/// Files that don't start with a snapshot header, or that were written in an unsupported
+/// version of the format, are rejected with [`io::ErrorKind::InvalidData`].
+pub fn read_snapshot<K, V>(path: impl AsRef<Path>) -> io::Result<Option<Snapshot<K, V>>>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+    };
+    let mut reader = BufReader::new(file);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let version = header
+        .trim_end()
+        .strip_prefix(HEADER)
+        .and_then(|version| version.strip_prefix(" v"))
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a snapshot file"))?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported snapshot version {version}"),
+        ));
+    }
+    Ok(Some(serde_json::from_reader(reader)?))
+}
+
+/// Writes the snapshot to an empty file, and waits until it has reached the disk.
+fn write_file<K, V>(file: &File, snapshot: &Snapshot<K, V>) -> io::Result<()>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "{HEADER} v{VERSION}")?;
+    serde_json::to_writer(&mut writer, snapshot)?;
+    let file = writer.into_inner().map_err(IntoInnerError::into_error)?;
+    file.sync_all()
+}
+
+/// Waits until the directory containing `path` has reached the disk, so that a file renamed
+/// into it isn't lost.
+///
+/// Directories can't be opened as files on every platform, so this does nothing outside Unix.
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(parent_dir(path))?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// The directory containing `path`, which is the current directory for a bare file name.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io, sync::Arc, time::Duration};
+
+    use tokio::{
+        sync::RwLock,
+        time::{interval, sleep},
+    };
+
+    use crate::{
+        cache::{Snapshot, TtlCache},
+        persistence::{read_snapshot, restore_from_file, start_periodic_persist, write_snapshot},
+    };
+
+    #[test]
+    fn given_a_written_snapshot_when_restoring_from_the_file_then_unexpired_entries_are_restored() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snapshot");
+        let mut cache = TtlCache::new();
+        cache.insert_with_ttl("key".to_owned(), "val".to_owned(), Duration::from_secs(60));
+        cache.insert_with_ttl("expired".to_owned(), "val".to_owned(), Duration::ZERO);
+        write_snapshot(&path, &cache.snapshot()).unwrap();
+
+        // Act
+        let mut restored = TtlCache::<String, String>::new();
+        let count = restore_from_file(&mut restored, &path).unwrap();
+
+        // Assert
+        assert_eq!(count, 1);
+        assert_eq!(restored.get("key").map(String::as_str), Some("val"));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn given_concurrent_writers_when_writing_snapshots_to_the_same_file_then_every_write_succeeds()
+    {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snapshot");
+        let mut cache = TtlCache::new();
+        for key in 0..1000 {
+            cache.insert_with_ttl(key, "val".to_owned(), Duration::from_secs(60));
+        }
+        let snapshot = cache.snapshot();
+
+        // Act
+        let written: Vec<_> = std::thread::scope(|scope| {
+            let writers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| write_snapshot(&path, &snapshot)))
+                .collect();
+            writers
+                .into_iter()
+                .map(|writer| writer.join().unwrap())
+                .collect()
+        });
+
+        // Assert
+        assert!(written.iter().all(Result::is_ok));
+        let read: Snapshot<u32, String> = read_snapshot(&path).unwrap().unwrap();
+        assert_eq!(read.len(), 1000);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn given_no_snapshot_file_when_restoring_from_it_then_nothing_is_restored() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = TtlCache::<String, String>::new();
+
+        // Act
+        let count = restore_from_file(&mut cache, dir.path().join("missing")).unwrap();
+
+        // Assert
+        assert_eq!(count, 0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn given_a_file_with_an_unsupported_version_when_reading_it_then_it_is_rejected() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snapshot");
+        fs::write(&path, "ttl_cache_with_purging snapshot v999\n{}").unwrap();
+
+        // Act
+        let read = read_snapshot::<String, String>(&path);
+
+        // Assert
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn when_the_persist_loop_runs_then_the_cache_is_written_to_the_file() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.snapshot");
+        let mut ttl_cache = TtlCache::new();
+        ttl_cache.insert_with_ttl("key".to_owned(), 1, Duration::from_secs(60));
+        let cache = Arc::new(RwLock::new(ttl_cache));
+
+        // Act
+        let _persist_handle =
+            start_periodic_persist(cache, path.clone(), interval(Duration::from_secs(10)));
+        sleep(Duration::from_millis(50)).await;
+
+        // Assert
+        let snapshot: Snapshot<String, u32> = read_snapshot(&path).unwrap().unwrap();
+        assert_eq!(snapshot.len(), 1);
+    }
+}
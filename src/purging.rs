@@ -1,23 +1,238 @@
 //! Strategies for purging expired cache entries.
-use std::sync::Arc;
+use std::{future::Future, ops::ControlFlow, sync::Arc};
 
-use tokio::{sync::RwLock, time::Interval};
+use tokio::{sync::RwLock, task::JoinHandle, time::Interval};
 
 use crate::cache::Purgeable;
 
 /// Kick-off a background task that will purge expired entries from the cache at the
 /// specified interval.
-pub fn start_periodic_purge<P>(cache: Arc<RwLock<P>>, mut purge_interval: Interval)
+///
+/// The task runs until the returned handle is stopped or dropped.
+pub fn start_periodic_purge<P>(cache: Arc<RwLock<P>>, purge_interval: Interval) -> PurgeHandle
 where
     P: Purgeable + Send + Sync + 'static,
 {
-    tokio::task::spawn(async move {
-        loop {
-            // Note that the first tick is instantaneous.
-            purge_interval.tick().await;
-            cache.write().await.purge_expired();
+    spawn_periodic(purge_interval, move || purge_all(cache.clone()))
+}
+
+/// Kick-off a background task that will purge expired entries from the cache at the
+/// specified interval, in chunks of at most `chunk_size` entries.
+///
+/// The cache's lock is released and the task yields between chunks, so readers aren't
+/// stalled while a large purge runs. See [`Purgeable::purge_expired_bounded`].
+///
+/// # Panics
+///
+/// Panics if `chunk_size` is zero.
+pub fn start_incremental_purge<P>(
+    cache: Arc<RwLock<P>>,
+    purge_interval: Interval,
+    chunk_size: usize,
+) -> PurgeHandle
+where
+    P: Purgeable + Send + Sync + 'static,
+{
+    assert!(
+        chunk_size > 0,
+        "an incremental purge needs a chunk size of at least one"
+    );
+    spawn_periodic(purge_interval, move || {
+        let cache = cache.clone();
+        async move {
+            while purge(&cache, Some(chunk_size)).await {
+                tokio::task::yield_now().await;
+            }
+            ControlFlow::Continue(())
+        }
+    })
+}
+
+/// Kick-off a background task that will purge expired entries from the cache at the
+/// specified interval, without keeping the cache alive.
+///
+/// The task only holds a [`Weak`](std::sync::Weak) reference to the cache, and ends itself on
+/// the first tick after every other owner of the cache is gone. It also stops when the
+/// returned handle is stopped or dropped, unless the handle is [detached](TaskHandle::detach).
+pub fn start_periodic_purge_weak<P>(cache: &Arc<RwLock<P>>, purge_interval: Interval) -> PurgeHandle
+where
+    P: Purgeable + Send + Sync + 'static,
+{
+    let cache = Arc::downgrade(cache);
+    spawn_periodic(purge_interval, move || {
+        let cache = cache.clone();
+        async move {
+            let Some(cache) = cache.upgrade() else {
+                return ControlFlow::Break(());
+            };
+            purge(&cache, None).await;
+            ControlFlow::Continue(())
         }
-    });
+    })
+}
+
+/// Kick-off a background task that will purge expired entries from the cache at the
+/// specified interval, until `cancellation_token` is cancelled.
+///
+/// The task also stops when the returned handle is stopped or dropped.
+#[cfg(feature = "tokio-util")]
+pub fn start_periodic_purge_with_cancellation<P>(
+    cache: Arc<RwLock<P>>,
+    purge_interval: Interval,
+    cancellation_token: tokio_util::sync::CancellationToken,
+) -> PurgeHandle
+where
+    P: Purgeable + Send + Sync + 'static,
+{
+    TaskHandle::spawn(async move {
+        tokio::select! {
+            _ = run_periodically(purge_interval, move || purge_all(cache.clone())) => {}
+            _ = cancellation_token.cancelled() => {}
+        }
+    })
+}
+
+/// Kick-off a background task that calls `tick` at the specified interval, until it returns
+/// [`ControlFlow::Break`].
+///
+/// The task also stops when the returned handle is stopped or dropped.
+pub(crate) fn spawn_periodic<F, Fut>(interval: Interval, tick: F) -> TaskHandle
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ControlFlow<()>> + Send + 'static,
+{
+    TaskHandle::spawn(run_periodically(interval, tick))
+}
+
+async fn run_periodically<F, Fut>(mut interval: Interval, mut tick: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ControlFlow<()>>,
+{
+    loop {
+        // Note that the first tick is instantaneous.
+        interval.tick().await;
+        if tick().await.is_break() {
+            return;
+        }
+    }
+}
+
+/// Purges every expired entry from the cache, as one tick of a periodic purge.
+async fn purge_all<P>(cache: Arc<RwLock<P>>) -> ControlFlow<()>
+where
+    P: Purgeable,
+{
+    purge(&cache, None).await;
+    ControlFlow::Continue(())
+}
+
+/// Purges expired entries from the cache while holding its write lock, at most
+/// `max_entries` of them if given, and returns whether there may be more to purge.
+///
+/// With the `tracing` feature, each purge runs in a `purge` span that records how long the
+/// lock was held, in microseconds, and for a [`TtlCache`](crate::cache::TtlCache), how many
+/// deadlines were scanned and entries removed.
+async fn purge<P>(cache: &RwLock<P>, max_entries: Option<usize>) -> bool
+where
+    P: Purgeable,
+{
+    let purge = async {
+        let mut cache = cache.write().await;
+        #[cfg(feature = "tracing")]
+        let locked_at = std::time::Instant::now();
+        let more = match max_entries {
+            Some(max_entries) => cache.purge_expired_bounded(max_entries),
+            None => {
+                cache.purge_expired();
+                false
+            }
+        };
+        drop(cache);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record(
+            "lock_held_us",
+            u64::try_from(locked_at.elapsed().as_micros()).unwrap_or(u64::MAX),
+        );
+        more
+    };
+    #[cfg(feature = "tracing")]
+    let purge = tracing::Instrument::instrument(
+        purge,
+        tracing::debug_span!(
+            "purge",
+            scanned = tracing::field::Empty,
+            removed = tracing::field::Empty,
+            lock_held_us = tracing::field::Empty,
+        ),
+    );
+    purge.await
+}
+
+/// A handle to a background purge task.
+pub type PurgeHandle = TaskHandle;
+
+/// A handle to a background task, such as a purge, persist or compaction task.
+///
+/// Dropping the handle stops the task. The task is only ever stopped while it is waiting
+/// for its next tick or for the cache's lock, never partway through a purge.
+#[must_use = "the task stops when its handle is dropped"]
+#[derive(Debug)]
+pub struct TaskHandle {
+    /// Only taken when the handle is consumed, so that it isn't aborted on drop.
+    task: Option<JoinHandle<()>>,
+}
+
+impl TaskHandle {
+    fn spawn<F>(task: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        TaskHandle {
+            task: Some(tokio::task::spawn(task)),
+        }
+    }
+
+    /// Signals the task to stop, without waiting for it to do so.
+    pub fn stop(&self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+
+    /// Stops the task and waits until it has stopped.
+    ///
+    /// A persist or compaction task that was writing a snapshot when it was stopped finishes
+    /// writing it on a blocking thread, after this returns, and a compaction may hold the
+    /// cache's read lock until then.
+    pub async fn stop_and_wait(mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    /// Lets the task keep running after the handle is dropped.
+    ///
+    /// Tasks started with [`start_periodic_purge_weak`] still end once the cache is dropped,
+    /// but other tasks will run for as long as the runtime does.
+    pub fn detach(mut self) {
+        // Dropping a `JoinHandle` detaches its task without aborting it.
+        drop(self.task.take());
+    }
+
+    /// Whether the task has stopped.
+    pub fn is_finished(&self) -> bool {
+        self.task.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
 }
 
 #[cfg(test)]
@@ -28,8 +243,13 @@ mod tests {
     use tokio::sync::RwLock;
     use tokio::time::{interval, sleep};
 
+    #[cfg(feature = "tracing")]
+    use crate::cache::test_helpers::SpanRecorder;
     use crate::cache::test_helpers::SpyCache;
-    use crate::purging::start_periodic_purge;
+    use crate::cache::{Purgeable, TtlCache};
+    use crate::purging::{
+        start_incremental_purge, start_periodic_purge, start_periodic_purge_weak,
+    };
 
     #[tokio::test]
     async fn when_the_purge_loop_runs_then_the_cache_deletes_expired_entries() {
@@ -37,10 +257,135 @@ mod tests {
         let cache = Arc::new(RwLock::new(SpyCache::default()));
 
         // Act
-        start_periodic_purge(cache.clone(), interval(Duration::from_secs(10000)));
+        let _purge_handle =
+            start_periodic_purge(cache.clone(), interval(Duration::from_secs(10000)));
 
         // Assert
         sleep(Duration::from_millis(10)).await;
         assert!(cache.write().await.purge_expired_called);
     }
+
+    #[tokio::test]
+    async fn given_a_running_purge_loop_when_stopping_and_waiting_then_the_cache_is_released() {
+        // Arrange
+        let cache = Arc::new(RwLock::new(SpyCache::default()));
+        let purge_handle = start_periodic_purge(cache.clone(), interval(Duration::from_secs(10)));
+
+        // Act
+        purge_handle.stop_and_wait().await;
+
+        // Assert
+        assert_eq!(Arc::strong_count(&cache), 1);
+    }
+
+    #[tokio::test]
+    async fn given_a_running_purge_loop_when_its_handle_is_dropped_then_the_loop_stops() {
+        // Arrange
+        let cache = Arc::new(RwLock::new(SpyCache::default()));
+        let purge_handle = start_periodic_purge(cache.clone(), interval(Duration::from_secs(10)));
+
+        // Act
+        drop(purge_handle);
+        sleep(Duration::from_millis(10)).await;
+
+        // Assert
+        assert_eq!(Arc::strong_count(&cache), 1);
+    }
+
+    #[tokio::test]
+    async fn given_many_expired_entries_when_an_incremental_purge_runs_then_they_are_all_purged_in_chunks(
+    ) {
+        // Arrange
+        let mut ttl_cache = TtlCache::new();
+        for key in 0..100 {
+            ttl_cache.insert_with_ttl(key, "val", Duration::ZERO);
+        }
+        let cache = Arc::new(RwLock::new(ttl_cache));
+
+        // Act
+        let _purge_handle =
+            start_incremental_purge(cache.clone(), interval(Duration::from_secs(10)), 8);
+
+        // Assert
+        sleep(Duration::from_millis(10)).await;
+        assert!(cache.read().await.is_empty());
+        assert!(!cache.write().await.purge_expired_bounded(8));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "chunk size")]
+    async fn given_a_chunk_size_of_zero_when_starting_an_incremental_purge_then_it_panics() {
+        // Arrange
+        let cache = Arc::new(RwLock::new(TtlCache::<u32, u32>::new()));
+
+        // Act
+        let _purge_handle = start_incremental_purge(cache, interval(Duration::from_secs(10)), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_detached_weak_purge_loop_when_the_cache_is_dropped_then_the_loop_ends() {
+        // Arrange
+        let runtime_metrics = tokio::runtime::Handle::current().metrics();
+        let cache = Arc::new(RwLock::new(SpyCache::default()));
+        let weak_cache = Arc::downgrade(&cache);
+        start_periodic_purge_weak(&cache, interval(Duration::from_secs(10))).detach();
+        sleep(Duration::from_millis(1)).await;
+        let purged_while_alive = cache.read().await.purge_expired_called;
+        let tasks_while_alive = runtime_metrics.num_alive_tasks();
+
+        // Act
+        drop(cache);
+        sleep(Duration::from_secs(10)).await;
+
+        // Assert
+        assert!(purged_while_alive);
+        assert_eq!(tasks_while_alive, 1);
+        assert!(weak_cache.upgrade().is_none());
+        assert_eq!(runtime_metrics.num_alive_tasks(), 0);
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[tokio::test]
+    async fn given_a_purge_loop_with_a_cancellation_token_when_the_token_is_cancelled_then_the_loop_stops(
+    ) {
+        // Arrange
+        let cache = Arc::new(RwLock::new(SpyCache::default()));
+        let token = tokio_util::sync::CancellationToken::new();
+        let purge_handle = crate::purging::start_periodic_purge_with_cancellation(
+            cache.clone(),
+            interval(Duration::from_secs(10)),
+            token.clone(),
+        );
+
+        // Act
+        token.cancel();
+        sleep(Duration::from_millis(10)).await;
+
+        // Assert
+        assert!(purge_handle.is_finished());
+        assert_eq!(Arc::strong_count(&cache), 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn given_tracing_is_enabled_when_a_purge_runs_then_its_span_records_the_work_done() {
+        // Arrange
+        let recorder = SpanRecorder::default();
+        let _subscriber = tracing::subscriber::set_default(recorder.clone());
+        let mut ttl_cache = TtlCache::new();
+        ttl_cache.insert_with_ttl("expired", "val", Duration::ZERO);
+        ttl_cache.insert_with_ttl("unexpired", "val", Duration::from_secs(60));
+        let cache = Arc::new(RwLock::new(ttl_cache));
+
+        // Act
+        let _purge_handle = start_periodic_purge(cache, interval(Duration::from_secs(10)));
+        sleep(Duration::from_millis(10)).await;
+
+        // Assert
+        let spans = recorder.spans("purge");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].get("scanned").map(String::as_str), Some("1"));
+        assert_eq!(spans[0].get("removed").map(String::as_str), Some("1"));
+        assert!(spans[0].contains_key("lock_held_us"));
+    }
 }
@@ -0,0 +1,358 @@
+//! A cache split into independently locked shards, for read throughput on many cores.
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{
+    sync::RwLock,
+    time::{interval_at, Instant},
+};
+
+use crate::{
+    cache::{Purgeable, TtlCache},
+    clock::{Clock, RealClock},
+    eviction::{EvictionPolicy, Unbounded},
+    purging::{start_periodic_purge, PurgeHandle},
+};
+
+/// A cloneable, thread-safe cache that is partitioned by key hash into shards, each with its
+/// own lock.
+///
+/// Writers and purges only lock the shard they touch, so readers of other shards are never
+/// blocked by them. Bounds such as capacity limits apply to each shard separately.
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use ttl_cache_with_purging::sharded::ShardedTtlCache;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let cache = ShardedTtlCache::new(16);
+/// cache.start_purging(Duration::from_secs(60));
+///
+/// cache.insert_with_ttl("key", "val", Duration::from_secs(3600)).await;
+/// assert_eq!(cache.get("key").await, Some("val"));
+/// # }
+/// ```
+pub struct ShardedTtlCache<K, V, P = Unbounded, C = RealClock> {
+    inner: Arc<Inner<K, V, P, C>>,
+}
+
+type Shard<K, V, P, C> = Arc<RwLock<TtlCache<K, V, P, C>>>;
+
+struct Inner<K, V, P, C> {
+    shards: Box<[Shard<K, V, P, C>]>,
+    hasher: RandomState,
+    purge_handles: Mutex<Vec<PurgeHandle>>,
+}
+
+impl<K, V, P, C> Clone for ShardedTtlCache<K, V, P, C> {
+    fn clone(&self) -> Self {
+        ShardedTtlCache {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V> ShardedTtlCache<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Creates a new cache with `shard_count` unbounded shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shard_count` is zero.
+    pub fn new(shard_count: usize) -> Self {
+        Self::with_shards(shard_count, |_shard| TtlCache::new())
+    }
+}
+
+impl<K, V, P, C> ShardedTtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Creates a new cache with `shard_count` shards, each created by `make_shard`, which is
+    /// given the index of the shard.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shard_count` is zero.
+    pub fn with_shards<F>(shard_count: usize, make_shard: F) -> Self
+    where
+        F: FnMut(usize) -> TtlCache<K, V, P, C>,
+    {
+        assert!(shard_count > 0, "a sharded cache needs at least one shard");
+        let shards = (0..shard_count)
+            .map(make_shard)
+            .map(|shard| Arc::new(RwLock::new(shard)))
+            .collect();
+        ShardedTtlCache {
+            inner: Arc::new(Inner {
+                shards,
+                hasher: RandomState::new(),
+                purge_handles: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// The number of shards the cache is split into.
+    pub fn shard_count(&self) -> usize {
+        self.inner.shards.len()
+    }
+
+    /// Retrieves a clone of an unexpired value from the cache.
+    pub async fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.shard(key).read().await.get(key).cloned()
+    }
+
+    /// Retrieves a clone of an unexpired value from the cache, along with the expiration.
+    pub async fn get_value_and_expiration<Q>(&self, key: &Q) -> Option<(V, Instant)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.shard(key)
+            .read()
+            .await
+            .get_value_and_expiration(key)
+            .map(|(val, expires_at)| (val.clone(), expires_at))
+    }
+
+    /// Adds a new value to the cache that will expire at the specified time.
+    pub async fn insert(&self, key: K, val: V, expires_at: Instant) {
+        let shard = self.shard(&key);
+        shard.write().await.insert(key, val, expires_at);
+    }
+
+    /// Adds a new value to the cache that will expire after the specified duration.
+    pub async fn insert_with_ttl(&self, key: K, val: V, ttl: Duration) {
+        let shard = self.shard(&key);
+        shard.write().await.insert_with_ttl(key, val, ttl);
+    }
+
+    /// Adds a new value to the cache that will expire after the shard's default TTL.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the shard was not built with a default TTL.
+    pub async fn insert_default(&self, key: K, val: V) {
+        let shard = self.shard(&key);
+        shard.write().await.insert_default(key, val);
+    }
+
+    /// Removes a key from the cache, returning the value if it was unexpired.
+    pub async fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key).write().await.remove(key)
+    }
+
+    /// Removes every entry for which the predicate returns `true`, locking one shard at a
+    /// time.
+    pub async fn invalidate_if<F>(&self, mut predicate: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        for shard in self.inner.shards.iter() {
+            shard.write().await.invalidate_if(&mut predicate);
+        }
+    }
+
+    /// Removes all entries from the cache, locking one shard at a time.
+    pub async fn clear(&self) {
+        for shard in self.inner.shards.iter() {
+            shard.write().await.clear();
+        }
+    }
+
+    /// Purges expired entries from the cache, locking one shard at a time.
+    pub async fn purge_expired(&self) {
+        for shard in self.inner.shards.iter() {
+            shard.write().await.purge_expired();
+        }
+    }
+
+    /// The number of entries in the cache, including expired entries that have not been
+    /// purged yet.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in self.inner.shards.iter() {
+            len += shard.read().await.len();
+        }
+        len
+    }
+
+    /// Whether the cache has no entries, including expired entries that have not been
+    /// purged yet.
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RwLock<TtlCache<K, V, P, C>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.inner.hasher.hash_one(key);
+        &self.inner.shards[(hash % self.inner.shards.len() as u64) as usize]
+    }
+}
+
+impl<K, V, P, C> ShardedTtlCache<K, V, P, C>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    P: EvictionPolicy<K> + Send + 'static,
+    C: Clock + Send + Sync + 'static,
+{
+    /// Starts a purge task for every shard, each purging expired entries every
+    /// `purge_period` while holding only its own shard's lock. Any purge tasks that were
+    /// already running are replaced.
+    ///
+    /// The tasks are staggered across the period so that the shards are not all purged at
+    /// once.
+    pub fn start_purging(&self, purge_period: Duration) {
+        let shard_count = self.inner.shards.len() as u32;
+        let start = Instant::now();
+        let purge_handles = self.inner.shards.iter().zip(0..).map(|(shard, i)| {
+            let purge_interval = interval_at(start + purge_period * i / shard_count, purge_period);
+            start_periodic_purge(shard.clone(), purge_interval)
+        });
+        *self.inner.purge_handles() = purge_handles.collect();
+    }
+
+    /// Stops purging expired entries, if purge tasks were running.
+    pub fn stop_purging(&self) {
+        self.inner.purge_handles().clear();
+    }
+}
+
+impl<K, V, P, C> Inner<K, V, P, C> {
+    fn purge_handles(&self) -> std::sync::MutexGuard<'_, Vec<PurgeHandle>> {
+        self.purge_handles
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::{advance, sleep, timeout};
+
+    use crate::cache::TtlCache;
+    use crate::eviction::EvictionPolicy;
+    use crate::sharded::ShardedTtlCache;
+
+    #[tokio::test]
+    async fn given_a_sharded_cache_when_adding_many_entries_then_they_are_spread_across_shards_and_readable(
+    ) {
+        // Arrange
+        let cache = ShardedTtlCache::new(8);
+
+        // Act
+        for key in 0..100 {
+            cache
+                .insert_with_ttl(key, key * 2, Duration::from_secs(60))
+                .await;
+        }
+
+        // Assert
+        assert_eq!(cache.len().await, 100);
+        for key in 0..100 {
+            assert_eq!(cache.get(&key).await, Some(key * 2));
+        }
+        let mut occupied_shards = 0;
+        for shard in cache.inner.shards.iter() {
+            occupied_shards += usize::from(!shard.read().await.is_empty());
+        }
+        assert!(occupied_shards > 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_sharded_cache_that_is_purging_when_entries_expire_then_every_shard_is_purged()
+    {
+        // Arrange
+        let cache = ShardedTtlCache::new(4);
+        for key in 0..20 {
+            cache
+                .insert_with_ttl(key, "val", Duration::from_secs(5))
+                .await;
+        }
+
+        // Act
+        cache.start_purging(Duration::from_secs(10));
+        advance(Duration::from_secs(10)).await;
+        sleep(Duration::from_millis(1)).await;
+
+        // Assert
+        assert!(cache.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn given_a_sharded_cache_with_bounded_shards_when_a_shard_is_full_then_it_evicts_independently(
+    ) {
+        // Arrange
+        let cache = ShardedTtlCache::with_shards(2, |_shard| TtlCache::with_capacity_limit(2));
+        let first_shard_keys = keys_in_shard(&cache, 0).take(3).collect::<Vec<_>>();
+        let second_shard_key = keys_in_shard(&cache, 1).next().unwrap();
+        cache
+            .insert_with_ttl(second_shard_key, "val", Duration::from_secs(60))
+            .await;
+
+        // Act
+        for &key in &first_shard_keys {
+            cache
+                .insert_with_ttl(key, "val", Duration::from_secs(60))
+                .await;
+        }
+
+        // Assert
+        assert_eq!(cache.len().await, 3);
+        assert!(cache.get(&first_shard_keys[0]).await.is_none());
+        assert_eq!(cache.get(&second_shard_key).await, Some("val"));
+    }
+
+    #[tokio::test]
+    async fn given_a_shard_that_is_locked_for_writing_when_reading_from_another_shard_then_the_read_is_not_blocked(
+    ) {
+        // Arrange
+        let cache = ShardedTtlCache::new(2);
+        let first_shard_key = keys_in_shard(&cache, 0).next().unwrap();
+        let second_shard_key = keys_in_shard(&cache, 1).next().unwrap();
+        cache
+            .insert_with_ttl(second_shard_key, "val", Duration::from_secs(60))
+            .await;
+        let _write_guard = cache.shard(&first_shard_key).write().await;
+
+        // Act
+        let read = timeout(Duration::from_secs(1), cache.get(&second_shard_key)).await;
+
+        // Assert
+        assert_eq!(read.ok(), Some(Some("val")));
+    }
+
+    /// The keys that are stored in the shard with the given index.
+    fn keys_in_shard<V, P: EvictionPolicy<u32>>(
+        cache: &ShardedTtlCache<u32, V, P>,
+        index: usize,
+    ) -> impl Iterator<Item = u32> + '_ {
+        (0..).filter(move |key| std::ptr::eq(cache.shard(key), &*cache.inner.shards[index]))
+    }
+}
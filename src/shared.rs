@@ -0,0 +1,509 @@
+//! A thread-safe cache handle that manages its own locking and purging.
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OnceCell, RwLock},
+    time::{Instant, Interval},
+};
+
+use crate::{
+    cache::{CacheStats, Expiration, Freshness, TtlCache},
+    clock::{Clock, RealClock},
+    eviction::{EvictionPolicy, Unbounded},
+    purging::{start_periodic_purge, PurgeHandle},
+};
+
+/// A cloneable, thread-safe handle to a [`TtlCache`].
+///
+/// Values are cloned out of the cache, so no lock guard is ever handed to the caller, and
+/// therefore can't be held across an `.await`. Clones share the same cache, and the purge
+/// task started by [`SharedTtlCache::start_purging`] is stopped once every clone is dropped.
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use tokio::time::interval;
+/// use ttl_cache_with_purging::{cache::TtlCache, shared::SharedTtlCache};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let cache = SharedTtlCache::new(TtlCache::new());
+/// cache.start_purging(interval(Duration::from_secs(60)));
+///
+/// cache.insert_with_ttl("key", "val", Duration::from_secs(3600)).await;
+/// assert_eq!(cache.get("key").await, Some("val"));
+/// # }
+/// ```
+pub struct SharedTtlCache<K, V, P = Unbounded, C = RealClock> {
+    inner: Arc<Inner<K, V, P, C>>,
+}
+
+struct Inner<K, V, P, C> {
+    cache: Arc<RwLock<TtlCache<K, V, P, C>>>,
+    purge_handle: Mutex<Option<PurgeHandle>>,
+    /// The loads that are in progress, which concurrent callers for the same key wait on.
+    loads: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+/// Forgets an in-progress load once it has succeeded or every caller is done with it, even if
+/// the callers were cancelled.
+struct LoadGuard<'a, K, V, P, C>
+where
+    K: Eq + Hash,
+{
+    inner: &'a Inner<K, V, P, C>,
+    key: &'a K,
+    load: Arc<OnceCell<V>>,
+}
+
+impl<K, V, P, C> Clone for SharedTtlCache<K, V, P, C> {
+    fn clone(&self) -> Self {
+        SharedTtlCache {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V, P, C> From<TtlCache<K, V, P, C>> for SharedTtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    fn from(cache: TtlCache<K, V, P, C>) -> Self {
+        Self::new(cache)
+    }
+}
+
+impl<K, V, P, C> SharedTtlCache<K, V, P, C>
+where
+    K: Eq + Hash,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    /// Wraps a cache so that it can be shared between tasks.
+    pub fn new(cache: TtlCache<K, V, P, C>) -> Self {
+        SharedTtlCache {
+            inner: Arc::new(Inner {
+                cache: Arc::new(RwLock::new(cache)),
+                purge_handle: Mutex::new(None),
+                loads: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Retrieves a clone of an unexpired value from the cache.
+    pub async fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.inner.cache.read().await.get(key).cloned()
+    }
+
+    /// Retrieves a clone of an unexpired value from the cache, along with the expiration.
+    pub async fn get_value_and_expiration<Q>(&self, key: &Q) -> Option<(V, Instant)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.inner
+            .cache
+            .read()
+            .await
+            .get_value_and_expiration(key)
+            .map(|(val, expires_at)| (val.clone(), expires_at))
+    }
+
+    /// Retrieves a clone of a value from the cache, even if it has expired, as long as it
+    /// expired within the cache's grace period.
+    ///
+    /// See [`TtlCache::get_stale`].
+    pub async fn get_stale<Q>(&self, key: &Q) -> Option<(V, Freshness)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.inner
+            .cache
+            .read()
+            .await
+            .get_stale(key)
+            .map(|(val, freshness)| (val.clone(), freshness))
+    }
+
+    /// Retrieves a clone of an unexpired value from the cache, or loads and inserts it if it
+    /// is missing or expired.
+    ///
+    /// The loader returns the value along with when it expires, either as an [`Instant`] or
+    /// as a [`Duration`]. Only one loader runs per key at a time: concurrent callers for the
+    /// same key wait for it, and share its value. If the loader fails, its error is returned
+    /// to its own caller only, and the next waiting caller runs its loader instead.
+    ///
+    /// ```rust
+    /// use std::{convert::Infallible, time::Duration};
+    ///
+    /// use ttl_cache_with_purging::{cache::TtlCache, shared::SharedTtlCache};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let cache = SharedTtlCache::new(TtlCache::new());
+    /// let val = cache
+    ///     .get_or_try_insert_with("key", || async {
+    ///         Ok::<_, Infallible>(("val", Duration::from_secs(3600)))
+    ///     })
+    ///     .await;
+    /// assert_eq!(val, Ok("val"));
+    /// # }
+    /// ```
+    pub async fn get_or_try_insert_with<F, Fut, X, E>(&self, key: K, loader: F) -> Result<V, E>
+    where
+        K: Clone,
+        V: Clone,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(V, X), E>>,
+        X: Into<Expiration>,
+    {
+        if let Some(val) = self.get(&key).await {
+            return Ok(val);
+        }
+
+        let load = LoadGuard::new(&self.inner, &key);
+        load.load
+            .get_or_try_init(|| async {
+                // A load that finished before this one was registered may have already
+                // inserted the value.
+                if let Some(val) = self.get(&key).await {
+                    return Ok(val);
+                }
+                let (val, expiration) = loader().await?;
+                self.insert_with_expiration(key.clone(), val.clone(), expiration)
+                    .await;
+                Ok(val)
+            })
+            .await
+            .cloned()
+    }
+
+    /// Adds a new value to the cache that will expire at the specified time.
+    pub async fn insert(&self, key: K, val: V, expires_at: Instant) {
+        self.inner.cache.write().await.insert(key, val, expires_at);
+    }
+
+    /// Adds a new value to the cache that will expire after the specified duration.
+    pub async fn insert_with_ttl(&self, key: K, val: V, ttl: Duration) {
+        self.inner
+            .cache
+            .write()
+            .await
+            .insert_with_ttl(key, val, ttl);
+    }
+
+    /// Adds a new value to the cache that will expire either at a specified time, or after a
+    /// specified duration.
+    pub async fn insert_with_expiration(&self, key: K, val: V, expiration: impl Into<Expiration>) {
+        self.inner
+            .cache
+            .write()
+            .await
+            .insert_with_expiration(key, val, expiration);
+    }
+
+    /// Adds a new value to the cache that will expire after the cache's default TTL.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache was not built with a default TTL.
+    pub async fn insert_default(&self, key: K, val: V) {
+        self.inner.cache.write().await.insert_default(key, val);
+    }
+
+    /// Removes a key from the cache, returning the value if it was unexpired.
+    pub async fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.inner.cache.write().await.remove(key)
+    }
+
+    /// Removes every entry for which the predicate returns `true`.
+    pub async fn invalidate_if<F>(&self, predicate: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.inner.cache.write().await.invalidate_if(predicate);
+    }
+
+    /// Removes all entries from the cache.
+    pub async fn clear(&self) {
+        self.inner.cache.write().await.clear();
+    }
+
+    /// The number of entries in the cache, including expired entries that have not been
+    /// purged yet.
+    pub async fn len(&self) -> usize {
+        self.inner.cache.read().await.len()
+    }
+
+    /// Whether the cache has no entries, including expired entries that have not been
+    /// purged yet.
+    pub async fn is_empty(&self) -> bool {
+        self.inner.cache.read().await.is_empty()
+    }
+
+    /// A snapshot of the cache's statistics, if it was built to record them.
+    pub async fn stats(&self) -> Option<CacheStats> {
+        self.inner.cache.read().await.stats()
+    }
+
+    /// Runs a closure with shared access to the underlying cache.
+    ///
+    /// The lock is released when the closure returns, so it can't be held across an
+    /// `.await`.
+    pub async fn read<R>(&self, f: impl FnOnce(&TtlCache<K, V, P, C>) -> R) -> R {
+        f(&*self.inner.cache.read().await)
+    }
+
+    /// Runs a closure with exclusive access to the underlying cache.
+    ///
+    /// The lock is released when the closure returns, so it can't be held across an
+    /// `.await`.
+    pub async fn write<R>(&self, f: impl FnOnce(&mut TtlCache<K, V, P, C>) -> R) -> R {
+        f(&mut *self.inner.cache.write().await)
+    }
+}
+
+impl<K, V, P, C> SharedTtlCache<K, V, P, C>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    P: EvictionPolicy<K> + Send + 'static,
+    C: Clock + Send + Sync + 'static,
+{
+    /// Starts purging expired entries at the specified interval, replacing any purge task
+    /// that was already running.
+    pub fn start_purging(&self, purge_interval: Interval) {
+        let purge_handle = start_periodic_purge(self.inner.cache.clone(), purge_interval);
+        *self.inner.purge_handle() = Some(purge_handle);
+    }
+
+    /// Stops purging expired entries, if a purge task was running.
+    pub fn stop_purging(&self) {
+        self.inner.purge_handle().take();
+    }
+}
+
+impl<K, V, P, C> Inner<K, V, P, C> {
+    fn purge_handle(&self) -> MutexGuard<'_, Option<PurgeHandle>> {
+        self.purge_handle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn loads(&self) -> MutexGuard<'_, HashMap<K, Arc<OnceCell<V>>>> {
+        self.loads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<'a, K, V, P, C> LoadGuard<'a, K, V, P, C>
+where
+    K: Clone + Eq + Hash,
+{
+    /// Joins the in-progress load for `key`, or registers a new one.
+    fn new(inner: &'a Inner<K, V, P, C>, key: &'a K) -> Self {
+        let load = inner.loads().entry(key.clone()).or_default().clone();
+        LoadGuard { inner, key, load }
+    }
+}
+
+impl<K, V, P, C> Drop for LoadGuard<'_, K, V, P, C>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        let mut loads = self.inner.loads();
+        // A later load may have replaced this one already. Callers still waiting on a failed
+        // load retry it, so it is kept for later callers to join instead of starting another
+        // loader alongside.
+        let registered = loads
+            .get(self.key)
+            .is_some_and(|load| Arc::ptr_eq(load, &self.load));
+        // Held by the map and this guard only.
+        let unshared = Arc::strong_count(&self.load) == 2;
+        if registered && (self.load.initialized() || unshared) {
+            loads.remove(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    use tokio::time::{advance, interval, sleep};
+
+    use crate::cache::TtlCache;
+    use crate::shared::SharedTtlCache;
+
+    #[tokio::test]
+    async fn given_a_shared_cache_when_a_clone_inserts_an_entry_then_every_clone_can_read_it() {
+        // Arrange
+        let cache = SharedTtlCache::new(TtlCache::new());
+        let clone = cache.clone();
+
+        // Act
+        clone
+            .insert_with_ttl("key", "val".to_string(), Duration::from_secs(60))
+            .await;
+
+        // Assert
+        assert_eq!(cache.get("key").await, Some("val".to_string()));
+        assert_eq!(cache.remove("key").await, Some("val".to_string()));
+        assert!(clone.is_empty().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_missing_entry_when_many_callers_load_it_at_once_then_the_loader_runs_once() {
+        // Arrange
+        let cache = SharedTtlCache::new(TtlCache::new());
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        // Act
+        let callers = (0..10).map(|_| {
+            let cache = cache.clone();
+            let loads = loads.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_try_insert_with("key", || async {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        sleep(Duration::from_secs(1)).await;
+                        Ok::<_, ()>(("val", Duration::from_secs(60)))
+                    })
+                    .await
+            })
+        });
+        let vals = join_all(callers).await;
+
+        // Assert
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(vals.into_iter().all(|val| val == Ok("val")));
+        assert_eq!(cache.get("key").await, Some("val"));
+        assert!(cache.inner.loads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn given_a_loader_that_fails_when_loading_an_entry_then_the_error_is_returned_and_nothing_is_cached(
+    ) {
+        // Arrange
+        let cache = SharedTtlCache::<&str, &str>::new(TtlCache::new());
+
+        // Act
+        let failed = cache
+            .get_or_try_insert_with("key", || async { Err::<(_, Duration), _>("unavailable") })
+            .await;
+        let retried = cache
+            .get_or_try_insert_with("key", || async {
+                Ok::<_, &str>(("val", Duration::from_secs(60)))
+            })
+            .await;
+
+        // Assert
+        assert_eq!(failed, Err("unavailable"));
+        assert_eq!(retried, Ok("val"));
+        assert!(cache.inner.loads().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_failed_load_with_a_waiting_caller_when_another_caller_arrives_then_only_one_loader_runs_at_a_time(
+    ) {
+        // Arrange
+        let cache = SharedTtlCache::new(TtlCache::new());
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        let load = |delay: Duration, result: Result<&'static str, &'static str>| {
+            let cache = cache.clone();
+            let running = running.clone();
+            let most_running = most_running.clone();
+            tokio::spawn(async move {
+                sleep(delay).await;
+                cache
+                    .get_or_try_insert_with("key", || async move {
+                        let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_running.fetch_max(now_running, Ordering::SeqCst);
+                        sleep(Duration::from_secs(1)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        result.map(|val| (val, Duration::from_secs(60)))
+                    })
+                    .await
+            })
+        };
+
+        // Act
+        let failing = load(Duration::ZERO, Err("unavailable"));
+        let waiting = load(Duration::from_millis(500), Ok("val"));
+        let late = load(Duration::from_millis(1500), Ok("late"));
+        let vals = join_all([failing, waiting, late]).await;
+
+        // Assert
+        assert_eq!(vals, [Err("unavailable"), Ok("val"), Ok("val")]);
+        assert_eq!(most_running.load(Ordering::SeqCst), 1);
+        assert!(cache.inner.loads().is_empty());
+    }
+
+    async fn join_all<T>(handles: impl IntoIterator<Item = tokio::task::JoinHandle<T>>) -> Vec<T> {
+        let handles: Vec<_> = handles.into_iter().collect();
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn given_a_shared_cache_that_is_purging_when_entries_expire_then_they_are_purged() {
+        // Arrange
+        let cache = SharedTtlCache::new(TtlCache::new());
+        cache
+            .insert_with_ttl("key", "val", Duration::from_secs(5))
+            .await;
+
+        // Act
+        cache.start_purging(interval(Duration::from_secs(10)));
+        advance(Duration::from_secs(10)).await;
+        sleep(Duration::from_millis(1)).await;
+
+        // Assert
+        assert!(cache.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn given_a_shared_cache_that_is_purging_when_every_handle_is_dropped_then_the_cache_is_released(
+    ) {
+        // Arrange
+        let cache = SharedTtlCache::<&str, &str>::new(TtlCache::new());
+        cache.start_purging(interval(Duration::from_secs(10)));
+        let underlying = Arc::downgrade(&cache.inner.cache);
+
+        // Act
+        drop(cache);
+        sleep(Duration::from_millis(10)).await;
+
+        // Assert
+        assert!(underlying.upgrade().is_none());
+    }
+}
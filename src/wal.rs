@@ -0,0 +1,580 @@
+//! A write-ahead log of cache changes, so that a crash loses none of them.
+//!
+//! The log lives in a directory next to a snapshot of the cache, in the format of
+//! [`persistence`](crate::persistence). Every insert, removal and purge is appended to the log
+//! as it happens, as a record made of its length and CRC-32 checksum, as little-endian `u32`s,
+//! followed by the change as JSON. Compacting the log writes a new snapshot and empties the
+//! log, and replaying it restores the snapshot and then reapplies the log.
+//!
+//! Periodic compaction rotates the log to a new file before writing the snapshot, and deletes
+//! the rotated log once the snapshot is written. Replaying reapplies a rotated log before the
+//! current one. Reapplying a log over a snapshot that already includes it leaves the same
+//! contents, so a crash at any point of a compaction loses no change.
+//!
+//! ```rust,no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use std::time::Duration;
+//!
+//! use ttl_cache_with_purging::{cache::TtlCache, wal::WriteAheadLog};
+//!
+//! let wal = WriteAheadLog::open("/var/cache/tokens")?;
+//! let mut cache = TtlCache::<String, String>::builder()
+//!     .write_ahead_log(wal.clone())
+//!     .build();
+//! wal.replay(&mut cache)?;
+//! cache.insert_with_ttl("key".to_owned(), "val".to_owned(), Duration::from_secs(60));
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fs::{self, File, OpenOptions},
+    hash::Hash,
+    io::{self, Write},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::{OwnedRwLockReadGuard, RwLock},
+    time::Interval,
+};
+
+use crate::{
+    cache::{SnapshotEntry, TtlCache},
+    clock::Clock,
+    eviction::EvictionPolicy,
+    persistence::{restore_from_file, sync_parent_dir, write_snapshot},
+    purging::{spawn_periodic, TaskHandle},
+};
+
+/// The name of the snapshot file in the log's directory.
+const SNAPSHOT_FILE: &str = "cache.snapshot";
+
+/// The name of the log file in the log's directory.
+const LOG_FILE: &str = "cache.wal";
+
+/// The name the log file is rotated to while a periodic compaction writes its snapshot.
+const ROTATED_LOG_FILE: &str = "cache.wal.old";
+
+/// The length of a record's header: its length, followed by its checksum.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// A write-ahead log that a [`TtlCache`] appends its changes to, as configured with
+/// [`TtlCacheBuilder::write_ahead_log`](crate::cache::TtlCacheBuilder::write_ahead_log).
+///
+/// Clones share the same log, so one clone can be given to a cache and another kept to
+/// replay and compact it.
+///
+/// Records are written to the log file as soon as they are appended, so they survive the
+/// process crashing, but only reach the disk once [synced](WriteAheadLog::sync) or compacted.
+/// Once an append fails, later appends are dropped until the log is compacted, since they
+/// would be unreadable after the failed record anyway.
+#[derive(Clone, Debug)]
+pub struct WriteAheadLog {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    rotated_log_path: PathBuf,
+    /// Held while a snapshot is written, so that an older snapshot can't replace a newer one.
+    compacting: Mutex<()>,
+    state: Mutex<LogState>,
+}
+
+#[derive(Debug)]
+struct LogState {
+    file: File,
+    /// Set while the log is being replayed, so that the replayed changes aren't appended again.
+    replaying: bool,
+    /// The first append that failed since the log was last compacted.
+    error: Option<io::Error>,
+}
+
+/// A change to a cache's contents.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Record<K, V> {
+    /// An entry was inserted, or changed in place, at the given wall-clock time.
+    Insert {
+        written_at: SystemTime,
+        entry: SnapshotEntry<K, V>,
+    },
+    /// An unexpired entry was removed or evicted.
+    Remove { key: K },
+    /// Expired entries were purged.
+    Purge { keys: Vec<K> },
+    /// Every entry was removed.
+    Clear,
+}
+
+/// Receives every change to a cache's contents.
+pub(crate) trait Journal<K, V> {
+    fn append(&self, record: Record<&K, &V>);
+}
+
+impl WriteAheadLog {
+    /// Opens the log in `dir`, creating the directory and an empty log if they don't exist.
+    ///
+    /// Opening the log doesn't read it. Call [`WriteAheadLog::replay`] once the cache has been
+    /// built.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let log_path = dir.join(LOG_FILE);
+        let file = open_log(&log_path)?;
+        Ok(WriteAheadLog {
+            inner: Arc::new(Inner {
+                snapshot_path: dir.join(SNAPSHOT_FILE),
+                log_path,
+                rotated_log_path: dir.join(ROTATED_LOG_FILE),
+                compacting: Mutex::default(),
+                state: Mutex::new(LogState {
+                    file,
+                    replaying: false,
+                    error: None,
+                }),
+            }),
+        })
+    }
+
+    /// Restores the snapshot into the cache and reapplies every change logged since, and then
+    /// compacts the log.
+    ///
+    /// Entries that have expired since they were logged are removed, rather than restored. The
+    /// log is read up to its first incomplete or corrupt record, which is where a crash
+    /// interrupted an append. The cache's removal listener is notified of logged removals as
+    /// they are reapplied.
+    pub fn replay<K, V, P, C>(&self, cache: &mut TtlCache<K, V, P, C>) -> io::Result<()>
+    where
+        K: Serialize + DeserializeOwned + Eq + Hash,
+        V: Serialize + DeserializeOwned,
+        P: EvictionPolicy<K>,
+        C: Clock,
+    {
+        self.state().replaying = true;
+        let replayed = self.replay_files(cache);
+        self.state().replaying = false;
+        replayed?;
+        self.compact(cache)
+    }
+
+    /// Writes the cache's unexpired contents to the snapshot file, and then empties the log.
+    ///
+    /// This clears any failed append, since the snapshot includes its change.
+    pub fn compact<K, V, P, C>(&self, cache: &TtlCache<K, V, P, C>) -> io::Result<()>
+    where
+        K: Serialize + Eq + Hash,
+        V: Serialize,
+        P: EvictionPolicy<K>,
+        C: Clock,
+    {
+        let _compacting = self.compacting();
+        let mut state = self.state();
+        write_snapshot(&self.inner.snapshot_path, &cache.borrowed_snapshot())?;
+        remove_if_exists(&self.inner.rotated_log_path)?;
+        state.file.set_len(0)?;
+        state.file.sync_all()?;
+        state.error = None;
+        Ok(())
+    }
+
+    /// Like [`WriteAheadLog::compact`], but only holds the cache's lock while the snapshot is
+    /// copied and the log is rotated, and writes the snapshot after releasing it.
+    ///
+    /// After a failed append, the logs are missing its change, so this compacts while holding
+    /// the lock instead. It does the same while a rotated log is left over from a compaction
+    /// that failed to write its snapshot, so as not to overwrite it.
+    fn compact_rotating<K, V, P, C>(
+        &self,
+        cache: OwnedRwLockReadGuard<TtlCache<K, V, P, C>>,
+    ) -> io::Result<()>
+    where
+        K: Serialize + Clone + Eq + Hash,
+        V: Serialize + Clone,
+        P: EvictionPolicy<K>,
+        C: Clock,
+    {
+        let compacting = self.compacting();
+        if !self.rotate()? {
+            drop(compacting);
+            return self.compact(&cache);
+        }
+        let snapshot = cache.snapshot();
+        drop(cache);
+        write_snapshot(&self.inner.snapshot_path, &snapshot)?;
+        remove_if_exists(&self.inner.rotated_log_path)
+    }
+
+    /// Renames the log to the rotated log, and starts appending to a new, empty log. Returns
+    /// `false`, without rotating, after a failed append or while a rotated log is left over.
+    fn rotate(&self) -> io::Result<bool> {
+        let mut state = self.state();
+        if state.error.is_some() || self.inner.rotated_log_path.exists() {
+            return Ok(false);
+        }
+        fs::rename(&self.inner.log_path, &self.inner.rotated_log_path)?;
+        let file = open_log(&self.inner.log_path).and_then(|file| {
+            sync_parent_dir(&self.inner.log_path)?;
+            Ok(file)
+        });
+        match file {
+            Ok(file) => {
+                state.file = file;
+                Ok(true)
+            }
+            Err(error) => {
+                let _ = fs::rename(&self.inner.rotated_log_path, &self.inner.log_path);
+                Err(error)
+            }
+        }
+    }
+
+    /// Waits until every appended record has reached the disk.
+    ///
+    /// Returns the error of the first append that failed since the log was last compacted.
+    pub fn sync(&self) -> io::Result<()> {
+        let state = self.state();
+        if let Some(error) = &state.error {
+            return Err(io::Error::new(error.kind(), error.to_string()));
+        }
+        state.file.sync_data()
+    }
+
+    fn replay_files<K, V, P, C>(&self, cache: &mut TtlCache<K, V, P, C>) -> io::Result<()>
+    where
+        K: DeserializeOwned + Eq + Hash,
+        V: DeserializeOwned,
+        P: EvictionPolicy<K>,
+        C: Clock,
+    {
+        restore_from_file(cache, &self.inner.snapshot_path)?;
+        replay_log(cache, &self.inner.rotated_log_path)?;
+        replay_log(cache, &self.inner.log_path)
+    }
+
+    fn compacting(&self) -> MutexGuard<'_, ()> {
+        self.inner
+            .compacting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn state(&self) -> MutexGuard<'_, LogState> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Reapplies every change in the log file at `path` to the cache, up to its first incomplete
+/// or corrupt record. A missing file has no changes.
+fn replay_log<K, V, P, C>(cache: &mut TtlCache<K, V, P, C>, path: &Path) -> io::Result<()>
+where
+    K: DeserializeOwned + Eq + Hash,
+    V: DeserializeOwned,
+    P: EvictionPolicy<K>,
+    C: Clock,
+{
+    let log = match fs::read(path) {
+        Ok(log) => log,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    let mut rest = log.as_slice();
+    while let Some((payload, next)) = next_record(rest) {
+        match serde_json::from_slice(payload)? {
+            Record::Insert { written_at, entry } => {
+                let elapsed = cache
+                    .clock()
+                    .system_now()
+                    .duration_since(written_at)
+                    .unwrap_or_default();
+                if let Err(key) = cache.restore_entry(entry, elapsed) {
+                    // The logged value replaced any earlier one, even though it has expired.
+                    cache.remove(&key);
+                }
+            }
+            Record::Remove { key } => {
+                cache.remove(&key);
+            }
+            Record::Purge { keys } => {
+                for key in keys {
+                    cache.remove(&key);
+                }
+            }
+            Record::Clear => cache.clear(),
+        }
+        rest = next;
+    }
+    Ok(())
+}
+
+impl<K, V> Journal<K, V> for WriteAheadLog
+where
+    K: Serialize,
+    V: Serialize,
+{
+    fn append(&self, record: Record<&K, &V>) {
+        let mut state = self.state();
+        if state.replaying || state.error.is_some() {
+            return;
+        }
+        if let Err(error) = write_record(&mut state.file, &record) {
+            state.error = Some(error);
+        }
+    }
+}
+
+/// Kick-off a background task that will compact the log against the cache at the specified
+/// interval.
+///
+/// The cache's read lock is only held while the snapshot is copied and the log is rotated to
+/// a new file, so that no change is made between them; the snapshot is written and synced
+/// after releasing it. Errors don't stop the task, which tries again on its next tick; with
+/// the `tracing` feature, they are logged as warnings.
+///
+/// The task runs until the returned handle is stopped or dropped.
+pub fn start_periodic_compaction<K, V, P, C>(
+    cache: Arc<RwLock<TtlCache<K, V, P, C>>>,
+    wal: WriteAheadLog,
+    compaction_interval: Interval,
+) -> TaskHandle
+where
+    K: Serialize + Clone + Eq + Hash + Send + Sync + 'static,
+    V: Serialize + Clone + Send + Sync + 'static,
+    P: EvictionPolicy<K> + Send + Sync + 'static,
+    C: Clock + Send + Sync + 'static,
+{
+    spawn_periodic(compaction_interval, move || {
+        let cache = cache.clone();
+        let wal = wal.clone();
+        async move {
+            let cache = cache.read_owned().await;
+            let compacted = tokio::task::spawn_blocking(move || wal.compact_rotating(cache))
+                .await
+                .unwrap_or_else(|error| Err(io::Error::other(error)));
+            #[cfg(feature = "tracing")]
+            if let Err(error) = &compacted {
+                tracing::warn!(%error, "failed to compact write-ahead log");
+            }
+            #[cfg(not(feature = "tracing"))]
+            let _ = compacted;
+            ControlFlow::Continue(())
+        }
+    })
+}
+
+/// Opens the log file at `path` for appending, creating it if it doesn't exist.
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Removes the file at `path`, unless there is no such file.
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Appends a record to the log in a single write.
+fn write_record<K, V>(file: &mut File, record: &Record<&K, &V>) -> io::Result<()>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    let payload = serde_json::to_vec(record)?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record is too large"))?;
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    file.write_all(&buf)
+}
+
+/// Splits the payload of the first record off the log, unless the log is empty or the record
+/// is incomplete or corrupt.
+fn next_record(log: &[u8]) -> Option<(&[u8], &[u8])> {
+    if log.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let (header, rest) = log.split_at(RECORD_HEADER_LEN);
+    let len = u32::from_le_bytes(header[..4].try_into().ok()?);
+    let checksum = u32::from_le_bytes(header[4..].try_into().ok()?);
+    let len = usize::try_from(len).ok().filter(|&len| len <= rest.len())?;
+    let (payload, rest) = rest.split_at(len);
+    (crc32fast::hash(payload) == checksum).then_some((payload, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, sync::Arc, time::Duration};
+
+    use tokio::{
+        sync::RwLock,
+        time::{interval, sleep},
+    };
+
+    use crate::{
+        cache::{Purgeable, TtlCache},
+        clock::MockClock,
+        persistence::read_snapshot,
+        wal::{
+            start_periodic_compaction, WriteAheadLog, LOG_FILE, ROTATED_LOG_FILE, SNAPSHOT_FILE,
+        },
+    };
+
+    #[test]
+    fn given_logged_changes_when_replaying_the_log_then_the_cache_is_rebuilt_without_expired_entries(
+    ) {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let clock = MockClock::new();
+        let wal = WriteAheadLog::open(dir.path()).unwrap();
+        let mut cache = TtlCache::builder()
+            .clock(clock.clone())
+            .write_ahead_log(wal.clone())
+            .build();
+        cache.insert_with_ttl("kept".to_owned(), 1, Duration::from_secs(60));
+        cache.insert_with_ttl("removed".to_owned(), 2, Duration::from_secs(60));
+        cache.insert_with_ttl("purged".to_owned(), 3, Duration::from_secs(1));
+        cache.insert_with_ttl("expiring".to_owned(), 4, Duration::from_secs(20));
+        cache.remove("removed");
+        clock.advance(Duration::from_secs(1));
+        cache.purge_expired();
+        wal.sync().unwrap();
+        drop(cache);
+
+        // Act
+        clock.advance(Duration::from_secs(30));
+        let wal = WriteAheadLog::open(dir.path()).unwrap();
+        let mut restored = TtlCache::<String, u32, _, _>::builder()
+            .clock(clock.clone())
+            .write_ahead_log(wal.clone())
+            .build();
+        wal.replay(&mut restored).unwrap();
+
+        // Assert
+        assert_eq!(restored.get("kept"), Some(&1));
+        assert_eq!(restored.len(), 1);
+        assert!(fs::read(dir.path().join(LOG_FILE)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn given_a_logged_overwrite_that_has_expired_when_replaying_then_the_snapshotted_value_is_not_restored(
+    ) {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let clock = MockClock::new();
+        let wal = WriteAheadLog::open(dir.path()).unwrap();
+        let mut cache = TtlCache::builder()
+            .clock(clock.clone())
+            .write_ahead_log(wal.clone())
+            .build();
+        cache.insert_with_ttl("token".to_owned(), 1, Duration::from_secs(3600));
+        wal.compact(&cache).unwrap();
+        cache.insert_with_ttl("token".to_owned(), 2, Duration::from_secs(10));
+        drop(cache);
+
+        // Act
+        clock.advance(Duration::from_secs(30));
+        let mut restored = TtlCache::<String, u32, _, _>::builder()
+            .clock(clock.clone())
+            .build();
+        wal.replay(&mut restored).unwrap();
+
+        // Assert
+        assert_eq!(restored.get("token"), None);
+        assert!(restored.is_empty());
+    }
+
+    #[test]
+    fn given_a_compacted_log_with_a_torn_record_when_replaying_then_intact_changes_are_kept() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WriteAheadLog::open(dir.path()).unwrap();
+        let mut cache = TtlCache::builder().write_ahead_log(wal.clone()).build();
+        cache.insert_with_ttl("snapshotted".to_owned(), 1, Duration::from_secs(60));
+        wal.compact(&cache).unwrap();
+        cache.insert_with_ttl("logged".to_owned(), 2, Duration::from_secs(60));
+        drop(cache);
+        let mut log = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(LOG_FILE))
+            .unwrap();
+        log.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+
+        // Act
+        let mut restored = TtlCache::<String, u32>::new();
+        wal.replay(&mut restored).unwrap();
+
+        // Assert
+        assert_eq!(restored.get("snapshotted"), Some(&1));
+        assert_eq!(restored.get("logged"), Some(&2));
+        assert_eq!(restored.len(), 2);
+    }
+
+    #[test]
+    fn given_a_compaction_interrupted_after_rotating_the_log_when_replaying_then_both_logs_are_reapplied(
+    ) {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WriteAheadLog::open(dir.path()).unwrap();
+        let mut cache = TtlCache::builder().write_ahead_log(wal.clone()).build();
+        cache.insert_with_ttl("snapshotted".to_owned(), 1, Duration::from_secs(60));
+        wal.compact(&cache).unwrap();
+        cache.insert_with_ttl("rotated".to_owned(), 2, Duration::from_secs(60));
+        assert!(wal.rotate().unwrap());
+        cache.insert_with_ttl("logged".to_owned(), 3, Duration::from_secs(60));
+        cache.remove("snapshotted");
+        drop(cache);
+
+        // Act
+        let mut restored = TtlCache::<String, u32>::new();
+        wal.replay(&mut restored).unwrap();
+
+        // Assert
+        assert_eq!(restored.get("rotated"), Some(&2));
+        assert_eq!(restored.get("logged"), Some(&3));
+        assert_eq!(restored.len(), 2);
+        assert!(!dir.path().join(ROTATED_LOG_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn when_the_compaction_loop_runs_then_the_snapshot_is_written_and_the_logs_are_emptied() {
+        // Arrange
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WriteAheadLog::open(dir.path()).unwrap();
+        let mut ttl_cache = TtlCache::builder().write_ahead_log(wal.clone()).build();
+        ttl_cache.insert_with_ttl("key".to_owned(), 1, Duration::from_secs(60));
+        let cache = Arc::new(RwLock::new(ttl_cache));
+
+        // Act
+        let _compaction_handle =
+            start_periodic_compaction(cache.clone(), wal, interval(Duration::from_secs(10)));
+        sleep(Duration::from_millis(50)).await;
+        cache
+            .write()
+            .await
+            .insert_with_ttl("logged".to_owned(), 2, Duration::from_secs(60));
+
+        // Assert
+        let snapshot = read_snapshot::<String, u32>(dir.path().join(SNAPSHOT_FILE));
+        assert_eq!(snapshot.unwrap().unwrap().len(), 1);
+        assert!(!dir.path().join(ROTATED_LOG_FILE).exists());
+        assert!(!fs::read(dir.path().join(LOG_FILE)).unwrap().is_empty());
+        let mut restored = TtlCache::<String, u32>::new();
+        WriteAheadLog::open(dir.path())
+            .unwrap()
+            .replay(&mut restored)
+            .unwrap();
+        assert_eq!(restored.len(), 2);
+    }
+}